@@ -19,13 +19,13 @@ async fn ws_handler(ws: WebSocket) {
 
         // Check message type and take appropriate actions.
         if msg.is_text() {
-            println!("{}", msg.into_text().unwrap());
+            println!("{}", msg.as_text().unwrap());
         } else if msg.is_binary() {
-            println!("{:?}", msg.into_bytes());
+            println!("{:?}", msg.as_bytes());
         }
 
-        // Send a text message.
-        let send_msg = Message::text("Hello world");
+        // Send a text message, a `Message` can be created from a `&str`, `String`, `Vec<u8>` or `Bytes`.
+        let send_msg = Message::from("Hello world");
         tx.send(send_msg).await.unwrap();
     }
 }
@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 use std::{convert::Infallible, net::SocketAddr};
 use tokio_tungstenite::tungstenite::protocol::Message as ClientMessage;
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 struct User {
     name: String,
@@ -22,7 +23,7 @@ async fn ws_handler(ws: WebSocket) {
         let msg = msg.unwrap();
 
         println!("{:?}", msg.close_reason());
-        println!("{}", String::from_utf8(msg.into_bytes()).unwrap());
+        println!("{}", String::from_utf8_lossy(msg.as_bytes()));
     }
 }
 
@@ -0,0 +1,228 @@
+use crate::middleware::Middleware;
+use crate::{ConnectionLimits, RateLimit, SessionExpiry, TrustedProxies, WebSocketConfig, WebSocketMiddleware};
+use hyper::{http::Extensions, Body, HeaderMap, Request, StatusCode};
+use std::fmt;
+use std::sync::Arc;
+
+type ResponseHookFn = dyn Fn(&Request<Body>, &mut HeaderMap) + Send + Sync;
+
+/// The hook customizing the headers of the response accepting the upgrade.
+#[derive(Clone)]
+pub(crate) struct ResponseHook(pub(crate) Arc<ResponseHookFn>);
+
+impl fmt::Debug for ResponseHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseHook").finish()
+    }
+}
+
+type GuardFn = dyn Fn(&Request<Body>, &mut Extensions) -> Result<(), StatusCode> + Send + Sync;
+
+/// A guard deciding whether to accept an upgrade request.
+#[derive(Clone)]
+pub(crate) struct Guard(pub(crate) Arc<GuardFn>);
+
+impl fmt::Debug for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Guard").finish()
+    }
+}
+
+/// The configuration used to upgrade the http requests to websocket connections.
+///
+/// It can be passed to [`upgrade_ws_with_config`](./fn.upgrade_ws_with_config.html). A plain [`WebSocketConfig`](./struct.WebSocketConfig.html)
+/// can also be passed there as it converts into an `UpgradeConfig` with the default options.
+///
+/// # Examples
+///
+/// ```
+/// use routerify_websocket::{ConnectionLimits, UpgradeConfig, WebSocketConfig};
+///
+/// let config = UpgradeConfig::new()
+///     .websocket_config(WebSocketConfig::default())
+///     .limits(ConnectionLimits::new().max_connections(1024));
+/// ```
+#[derive(Debug, Clone)]
+pub struct UpgradeConfig {
+    pub(crate) ws_config: WebSocketConfig,
+    pub(crate) limits: Option<ConnectionLimits>,
+    pub(crate) rate_limit: Option<RateLimit>,
+    pub(crate) trusted_proxies: Option<TrustedProxies>,
+    pub(crate) subprotocols: Vec<String>,
+    pub(crate) require_subprotocol: bool,
+    pub(crate) stream_fragmented: bool,
+    pub(crate) close_on_error: bool,
+    pub(crate) response_hook: Option<ResponseHook>,
+    pub(crate) guards: Vec<Guard>,
+    pub(crate) middlewares: Vec<Middleware>,
+    pub(crate) session: Option<SessionExpiry>,
+}
+
+impl Default for UpgradeConfig {
+    fn default() -> Self {
+        UpgradeConfig {
+            ws_config: WebSocketConfig::default(),
+            limits: None,
+            rate_limit: None,
+            trusted_proxies: None,
+            subprotocols: Vec::new(),
+            require_subprotocol: false,
+            stream_fragmented: false,
+            close_on_error: true,
+            response_hook: None,
+            guards: Vec::new(),
+            middlewares: Vec::new(),
+            session: None,
+        }
+    }
+}
+
+impl UpgradeConfig {
+    /// Creates a new `UpgradeConfig` with the default options.
+    pub fn new() -> Self {
+        UpgradeConfig::default()
+    }
+
+    /// Sets the [`WebSocketConfig`](./struct.WebSocketConfig.html) used for the upgraded connections.
+    pub fn websocket_config(mut self, config: WebSocketConfig) -> Self {
+        self.ws_config = config;
+        self
+    }
+
+    /// Sets the [`ConnectionLimits`](./struct.ConnectionLimits.html) enforced before upgrading the connections.
+    pub fn limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Sets the [`RateLimit`](./struct.RateLimit.html) applied to the incoming messages of each connection.
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Sets the [`TrustedProxies`](./struct.TrustedProxies.html) allowed to report the client address.
+    ///
+    /// The resolved client address is also used for the per IP [connection limits](./struct.ConnectionLimits.html).
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = Some(proxies);
+        self
+    }
+
+    /// Sets the subprotocols supported by the server, in order of preference.
+    ///
+    /// The first supported subprotocol offered by the client in the `Sec-WebSocket-Protocol` header is selected and
+    /// is available as [`WebSocket::subprotocol`](./struct.WebSocket.html#method.subprotocol).
+    pub fn subprotocols<I, S>(mut self, subprotocols: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.subprotocols = subprotocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Rejects the upgrade requests which don't offer any of the supported [subprotocols](#method.subprotocols).
+    pub fn require_subprotocol(mut self, require: bool) -> Self {
+        self.require_subprotocol = require;
+        self
+    }
+
+    /// Streams the incoming fragmented messages as their frames arrive with
+    /// [`WebSocket::next_incoming`](./struct.WebSocket.html#method.next_incoming), instead of buffering them whole.
+    ///
+    /// The `Stream` implementation of the [`WebSocket`](./struct.WebSocket.html) still returns them whole, bounded by
+    /// the `max_message_size` of the [`WebSocketConfig`](./struct.WebSocketConfig.html).
+    pub fn stream_fragmented(mut self, stream: bool) -> Self {
+        self.stream_fragmented = stream;
+        self
+    }
+
+    /// Sends a close frame to the peer before returning a protocol violation (`1002`), an invalid `UTF-8` text
+    /// (`1007`) or an oversized message (`1009`) error from the [`WebSocket`](./struct.WebSocket.html) stream, so
+    /// the clients see why the connection was closed.
+    ///
+    /// It is enabled by default.
+    pub fn close_on_error(mut self, close: bool) -> Self {
+        self.close_on_error = close;
+        self
+    }
+
+    /// Sets a hook to customize the headers of the `101 Switching Protocols` response accepting the upgrade, e.g. to
+    /// refresh a session cookie.
+    ///
+    /// The hook receives the upgrade request and the response headers, which already contain the handshake headers.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hyper::header::{self, HeaderValue};
+    /// use routerify_websocket::UpgradeConfig;
+    ///
+    /// let config = UpgradeConfig::new().on_upgrade_response(|req, headers| {
+    ///     if let Some(id) = req.headers().get("x-request-id") {
+    ///         headers.insert("x-request-id", id.clone());
+    ///     }
+    ///     headers.append(header::SET_COOKIE, HeaderValue::from_static("session=refreshed; HttpOnly"));
+    /// });
+    /// ```
+    pub fn on_upgrade_response<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Request<Body>, &mut HeaderMap) + Send + Sync + 'static,
+    {
+        self.response_hook = Some(ResponseHook(Arc::new(hook)));
+        self
+    }
+
+    /// Adds a guard deciding whether to accept an upgrade request, e.g. to authenticate the client.
+    ///
+    /// The guards run in the order they were added, before the [connection limits](./struct.ConnectionLimits.html)
+    /// are enforced. A guard rejects the request by returning the status code of the response, and can attach data to
+    /// the connection by inserting it into the extensions, which are available as
+    /// [`WebSocket::extensions`](./struct.WebSocket.html#method.extensions) along with the extensions of the request.
+    /// The guards also run on the requests posting the messages of the fallback sessions.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use hyper::{header, StatusCode};
+    /// use routerify_websocket::UpgradeConfig;
+    ///
+    /// struct User(String);
+    ///
+    /// let config = UpgradeConfig::new().guard(|req, extensions| {
+    ///     let token = req.headers().get(header::AUTHORIZATION).ok_or(StatusCode::UNAUTHORIZED)?;
+    ///     let user = token.to_str().map_err(|_| StatusCode::UNAUTHORIZED)?;
+    ///     extensions.insert(User(user.to_owned()));
+    ///     Ok(())
+    /// });
+    /// ```
+    pub fn guard<F>(mut self, guard: F) -> Self
+    where
+        F: Fn(&Request<Body>, &mut Extensions) -> Result<(), StatusCode> + Send + Sync + 'static,
+    {
+        self.guards.push(Guard(Arc::new(guard)));
+        self
+    }
+
+    /// Adds a [`WebSocketMiddleware`](./trait.WebSocketMiddleware.html) running on the messages of the connections.
+    ///
+    /// The incoming messages go through the middlewares in the order they were added, and the outgoing messages in the
+    /// reverse order.
+    pub fn middleware<M: WebSocketMiddleware>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Middleware(Arc::new(middleware)));
+        self
+    }
+
+    /// Sets the [`SessionExpiry`](./struct.SessionExpiry.html) closing the connections once their session expires.
+    pub fn session_expiry(mut self, session: SessionExpiry) -> Self {
+        self.session = Some(session);
+        self
+    }
+}
+
+impl From<WebSocketConfig> for UpgradeConfig {
+    fn from(config: WebSocketConfig) -> Self {
+        UpgradeConfig::new().websocket_config(config)
+    }
+}
@@ -1,11 +1,51 @@
+#![allow(non_local_definitions)]
+
+use crate::CloseCode;
 use derive_more::Display;
 use std::fmt::{self, Debug, Display, Formatter};
+use std::io;
+use tokio_tungstenite::tungstenite::error::{CapacityError, Error as TungsteniteError, ProtocolError};
 
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
+/// The kind of a [`WebsocketError`](./enum.WebsocketError.html), returned by
+/// [`WebsocketError::kind`](./enum.WebsocketError.html#method.kind).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The connection has been closed after the closing handshake.
+    ConnectionClosed,
+    /// The connection was already closed when it was used again.
+    AlreadyClosed,
+    /// The peer violated the websocket protocol.
+    Protocol,
+    /// A message exceeded the `max_message_size` or a frame the `max_frame_size`.
+    Capacity,
+    /// A text message wasn't valid `UTF-8`.
+    Utf8,
+    /// The underlying connection failed.
+    Io,
+    /// A message couldn't be encoded or decoded as `JSON`.
+    Json,
+    /// The connection exceeded its [rate limit](./struct.RateLimit.html).
+    RateLimit,
+    /// The websocket upgrade failed.
+    Upgrade,
+    /// A [middleware](./trait.WebSocketMiddleware.html) closed the connection.
+    Middleware,
+    /// The [session](./struct.SessionExpiry.html) of the connection expired.
+    SessionExpired,
+    /// The message can't be carried by the transport of the connection, e.g. a raw frame over the
+    /// [fallback](./fallback/index.html) transport.
+    Unsupported,
+    /// Any other error, e.g. returned by the stream of a streamed message.
+    Other,
+}
+
 /// A set of errors that can occur during handling the websocket connections and in other operations.
 #[derive(Display)]
 #[display(fmt = "routerify-websocket: {}")]
+#[non_exhaustive]
 pub enum WebsocketError {
     /// Websocket upgrade error.
     #[display(fmt = "Websocket upgrade error: {}", _0)]
@@ -45,8 +85,23 @@ pub enum WebsocketError {
     #[display(fmt = "Failed to close the websocket connection: {}", _0)]
     WebSocketClose(BoxError),
 
-    #[doc(hidden)]
-    __Nonexhaustive,
+    /// The connection exceeded its [rate limit](./struct.RateLimit.html) and has been closed.
+    #[display(fmt = "The connection exceeded its rate limit and has been closed")]
+    RateLimitExceeded,
+
+    /// A [middleware](./trait.WebSocketMiddleware.html) closed the connection with the code.
+    #[display(fmt = "The connection has been closed by a middleware with the code {}", _0)]
+    ClosedByMiddleware(CloseCode),
+
+    /// The [session](./struct.SessionExpiry.html) of the connection expired and the connection has been closed with the
+    /// code.
+    #[display(fmt = "The session expired and the connection has been closed with the code {}", _0)]
+    SessionExpired(CloseCode),
+
+    /// The message can't be carried by the transport of the connection, e.g. a raw frame over the
+    /// [fallback](./fallback/index.html) transport. The connection is still usable.
+    #[display(fmt = "The message is not supported by the transport of the connection")]
+    UnsupportedMessage,
 }
 
 impl Debug for WebsocketError {
@@ -55,11 +110,212 @@ impl Debug for WebsocketError {
     }
 }
 
-impl std::error::Error for WebsocketError {}
+impl WebsocketError {
+    fn inner(&self) -> Option<&BoxError> {
+        match self {
+            WebsocketError::Upgrade(err)
+            | WebsocketError::MessageReceive(err)
+            | WebsocketError::ReadyStatus(err)
+            | WebsocketError::MessageSend(err)
+            | WebsocketError::MessageFlush(err)
+            | WebsocketError::DecodeText(err)
+            | WebsocketError::WebSocketClose(err) => Some(err),
+            #[cfg(feature = "json")]
+            WebsocketError::DecodeJson(err) | WebsocketError::EncodeJson(err) => Some(err),
+            WebsocketError::RateLimitExceeded
+            | WebsocketError::ClosedByMiddleware(_)
+            | WebsocketError::SessionExpired(_)
+            | WebsocketError::UnsupportedMessage => None,
+        }
+    }
+
+    /// Returns the kind of this error, found from its cause.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use routerify_websocket::{ErrorKind, WebSocket};
+    ///
+    /// async fn ws_handler(mut ws: WebSocket) {
+    ///     while let Some(msg) = ws.next().await {
+    ///         match msg {
+    ///             Ok(msg) => println!("{:?}", msg),
+    ///             Err(err) if err.kind() == ErrorKind::Capacity => println!("The message is too large"),
+    ///             Err(err) => {
+    ///                 eprintln!("{}", err);
+    ///                 break;
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            WebsocketError::Upgrade(_) => return ErrorKind::Upgrade,
+            WebsocketError::DecodeText(_) => return ErrorKind::Utf8,
+            #[cfg(feature = "json")]
+            WebsocketError::DecodeJson(_) | WebsocketError::EncodeJson(_) => return ErrorKind::Json,
+            WebsocketError::RateLimitExceeded => return ErrorKind::RateLimit,
+            WebsocketError::ClosedByMiddleware(_) => return ErrorKind::Middleware,
+            WebsocketError::SessionExpired(_) => return ErrorKind::SessionExpired,
+            WebsocketError::UnsupportedMessage => return ErrorKind::Unsupported,
+            _ => {}
+        }
+
+        let mut cause = self.inner().map(|err| &**err as &(dyn std::error::Error + 'static));
+        while let Some(err) = cause {
+            if let Some(kind) = kind_of(err) {
+                return kind;
+            }
+            cause = err.source();
+        }
+        ErrorKind::Other
+    }
+
+    /// Returns the [`tungstenite`](https://docs.rs/tungstenite) error this error was caused by, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use routerify_websocket::{TungsteniteError, WebSocket};
+    ///
+    /// async fn ws_handler(mut ws: WebSocket) {
+    ///     while let Some(msg) = ws.next().await {
+    ///         match msg {
+    ///             Ok(msg) => println!("{:?}", msg),
+    ///             Err(err) => {
+    ///                 if let Some(TungsteniteError::Protocol(err)) = err.tungstenite_error() {
+    ///                     eprintln!("The client violated the protocol: {}", err);
+    ///                 }
+    ///                 break;
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn tungstenite_error(&self) -> Option<&TungsteniteError> {
+        let mut cause = self.inner().map(|err| &**err as &(dyn std::error::Error + 'static));
+        while let Some(err) = cause {
+            if let Some(err) = err.downcast_ref::<TungsteniteError>() {
+                return Some(err);
+            }
+            cause = err.source();
+        }
+        None
+    }
+
+    /// Returns true if the connection is closed, either after the closing handshake or when it was used again.
+    pub fn is_connection_closed(&self) -> bool {
+        matches!(self.kind(), ErrorKind::ConnectionClosed | ErrorKind::AlreadyClosed)
+    }
+
+    /// Returns true if the peer violated the websocket protocol.
+    pub fn is_protocol_error(&self) -> bool {
+        self.kind() == ErrorKind::Protocol
+    }
+
+    /// Returns true if a message or a frame exceeded the configured size.
+    pub fn is_capacity_error(&self) -> bool {
+        self.kind() == ErrorKind::Capacity
+    }
+
+    /// Returns true if a text message wasn't valid `UTF-8`.
+    pub fn is_utf8_error(&self) -> bool {
+        self.kind() == ErrorKind::Utf8
+    }
+
+    /// Returns true if the underlying connection failed.
+    pub fn is_io_error(&self) -> bool {
+        self.kind() == ErrorKind::Io
+    }
+
+    /// Returns the close code to send back to the peer for this error, or `None` if the connection can't be closed
+    /// gracefully anymore.
+    ///
+    /// | Kind | Close code |
+    /// | --- | --- |
+    /// | `Protocol` | `1002` [`Protocol`](./enum.CloseCode.html#variant.Protocol) |
+    /// | `Utf8`, `Json` | `1007` [`Invalid`](./enum.CloseCode.html#variant.Invalid) |
+    /// | `RateLimit` | `1008` [`Policy`](./enum.CloseCode.html#variant.Policy) |
+    /// | `Capacity` | `1009` [`Size`](./enum.CloseCode.html#variant.Size) |
+    /// | `Other` | `1011` [`Error`](./enum.CloseCode.html#variant.Error) |
+    /// | `Middleware` | The code sent by the middleware |
+    /// | `SessionExpired` | The code of the [`SessionExpiry`](./struct.SessionExpiry.html), `4001` by default |
+    /// | `ConnectionClosed`, `AlreadyClosed`, `Io`, `Upgrade`, `Unsupported` | `None` |
+    pub fn close_code(&self) -> Option<CloseCode> {
+        if let WebsocketError::ClosedByMiddleware(code) | WebsocketError::SessionExpired(code) = self {
+            return Some(*code);
+        }
+
+        match self.kind() {
+            ErrorKind::Protocol => Some(CloseCode::Protocol),
+            ErrorKind::Utf8 | ErrorKind::Json => Some(CloseCode::Invalid),
+            ErrorKind::RateLimit => Some(CloseCode::Policy),
+            ErrorKind::Capacity => Some(CloseCode::Size),
+            ErrorKind::Other => Some(CloseCode::Error),
+            ErrorKind::Middleware
+            | ErrorKind::SessionExpired
+            | ErrorKind::ConnectionClosed
+            | ErrorKind::AlreadyClosed
+            | ErrorKind::Io
+            | ErrorKind::Upgrade
+            | ErrorKind::Unsupported => None,
+        }
+    }
+}
+
+fn kind_of(err: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+    if let Some(err) = err.downcast_ref::<TungsteniteError>() {
+        return Some(match err {
+            TungsteniteError::ConnectionClosed => ErrorKind::ConnectionClosed,
+            TungsteniteError::AlreadyClosed => ErrorKind::AlreadyClosed,
+            TungsteniteError::Io(err) => io_kind(err),
+            TungsteniteError::Protocol(ProtocolError::ResetWithoutClosingHandshake) => ErrorKind::Io,
+            TungsteniteError::Protocol(_) | TungsteniteError::AttackAttempt => ErrorKind::Protocol,
+            TungsteniteError::Capacity(_) | TungsteniteError::WriteBufferFull(_) => ErrorKind::Capacity,
+            TungsteniteError::Utf8 => ErrorKind::Utf8,
+            _ => ErrorKind::Other,
+        });
+    }
+
+    if let Some(err) = err.downcast_ref::<io::Error>() {
+        Some(io_kind(err))
+    } else if err.is::<ProtocolError>() {
+        Some(ErrorKind::Protocol)
+    } else if err.is::<CapacityError>() {
+        Some(ErrorKind::Capacity)
+    } else if err.is::<std::str::Utf8Error>() || err.is::<std::string::FromUtf8Error>() {
+        Some(ErrorKind::Utf8)
+    } else {
+        None
+    }
+}
+
+/// The protocol violations and the oversized frames detected while reading the connection are reported as
+/// `InvalidData` IO errors.
+fn io_kind(err: &io::Error) -> ErrorKind {
+    match err.get_ref() {
+        Some(inner) if inner.is::<ProtocolError>() => ErrorKind::Protocol,
+        Some(inner) if inner.is::<CapacityError>() => ErrorKind::Capacity,
+        _ => ErrorKind::Io,
+    }
+}
+
+impl std::error::Error for WebsocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner().map(|err| &**err as &(dyn std::error::Error + 'static))
+    }
+}
 
+/// Two errors are equal if they are the same variant, of the same [kind](#method.kind) and with the same
+/// [close code](#method.close_code). Their causes are not compared.
 impl PartialEq for WebsocketError {
     fn eq(&self, other: &Self) -> bool {
-        self.to_string().eq(&other.to_string())
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+            && self.kind() == other.kind()
+            && self.close_code() == other.close_code()
     }
 }
 
@@ -0,0 +1,190 @@
+use crate::upgrade::upgrade_with;
+use crate::{Bytes, CloseCode, Message, UpgradeConfig, Utf8Bytes, WebSocket, WebSocketConfig, WebsocketError};
+use futures::future::Ready;
+use futures::{SinkExt, StreamExt};
+use hyper::{body::HttpBody, Request, Response};
+use std::borrow::Cow;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::protocol;
+
+/// The event hooks of a websocket connection, an alternative to writing the read loop in a handler.
+///
+/// The connections upgraded with [`upgrade_ws_events`](./fn.upgrade_ws_events.html) are driven by the crate: the
+/// incoming messages are read and dispatched to the matching hook, the `Ping` messages are answered automatically and
+/// the `Pong` messages are swallowed. Every hook has a no-op default implementation and receives an
+/// [`EventContext`](./struct.EventContext.html) to reply through.
+///
+/// The hooks of a connection are called one at a time, the next message is read only after the previous hook returns.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use hyper::Body;
+/// # use routerify::Router;
+/// use routerify_websocket::{upgrade_ws_events, CloseCode, EventContext, Message, Utf8Bytes, WebSocketEvents};
+/// # use std::convert::Infallible;
+///
+/// struct Echo;
+///
+/// impl WebSocketEvents for Echo {
+///     async fn on_open(&self, ctx: &mut EventContext) {
+///         println!("New websocket connection: {}", ctx.remote_addr());
+///     }
+///
+///     async fn on_text(&self, ctx: &mut EventContext, text: Utf8Bytes) {
+///         ctx.send(Message::from(text)).await.unwrap();
+///     }
+///
+///     async fn on_close(&self, _ctx: &mut EventContext, code: Option<CloseCode>, reason: String) {
+///         println!("Connection closed: {:?} {}", code, reason);
+///     }
+/// }
+///
+/// fn router() -> Router<Body, Infallible> {
+///     Router::builder()
+///         .any_method("/ws", upgrade_ws_events(Echo))
+///         .build()
+///         .unwrap()
+/// }
+/// ```
+pub trait WebSocketEvents: Send + Sync + 'static {
+    /// Called once the connection is upgraded, before any message is read.
+    fn on_open(&self, ctx: &mut EventContext) -> impl Future<Output = ()> + Send {
+        let _ = ctx;
+        async {}
+    }
+
+    /// Called when a `Text` message is received, with its payload shared without copying.
+    fn on_text(&self, ctx: &mut EventContext, text: Utf8Bytes) -> impl Future<Output = ()> + Send {
+        let _ = (ctx, text);
+        async {}
+    }
+
+    /// Called when a `Binary` message is received, with its payload shared without copying.
+    fn on_binary(&self, ctx: &mut EventContext, data: Bytes) -> impl Future<Output = ()> + Send {
+        let _ = (ctx, data);
+        async {}
+    }
+
+    /// Called when the connection is closed, with the code and reason of the `Close` message if the peer sent one.
+    ///
+    /// It is not called if the connection ends with an error.
+    fn on_close(
+        &self,
+        ctx: &mut EventContext,
+        code: Option<CloseCode>,
+        reason: String,
+    ) -> impl Future<Output = ()> + Send {
+        let _ = (ctx, code, reason);
+        async {}
+    }
+
+    /// Called when reading from the connection fails. The connection is not read anymore afterwards.
+    fn on_error(&self, ctx: &mut EventContext, err: WebsocketError) -> impl Future<Output = ()> + Send {
+        let _ = (ctx, err);
+        async {}
+    }
+}
+
+/// The context passed to the [`WebSocketEvents`](./trait.WebSocketEvents.html) hooks to reply through.
+#[derive(Debug)]
+pub struct EventContext {
+    ws: WebSocket,
+}
+
+impl EventContext {
+    /// Sends a message to the connection.
+    pub async fn send(&mut self, msg: Message) -> crate::Result<()> {
+        self.ws.send(msg).await
+    }
+
+    /// Starts closing the connection with a code and reason.
+    ///
+    /// The [`on_close`](./trait.WebSocketEvents.html#method.on_close) hook is called once the peer acknowledges it.
+    pub async fn close_with<R: Into<Cow<'static, str>>>(&mut self, code: CloseCode, reason: R) -> crate::Result<()> {
+        self.ws.send(Message::close_with(code, reason)).await
+    }
+
+    /// Get the peer's remote address.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.ws.remote_addr()
+    }
+
+    /// Get the underlying websocket connection.
+    pub fn websocket(&self) -> &WebSocket {
+        &self.ws
+    }
+
+    /// Get the underlying websocket connection mutably.
+    pub fn websocket_mut(&mut self) -> &mut WebSocket {
+        &mut self.ws
+    }
+}
+
+async fn drive<T: WebSocketEvents>(events: Arc<T>, ws: WebSocket) {
+    let mut ctx = EventContext { ws };
+
+    events.on_open(&mut ctx).await;
+
+    loop {
+        match ctx.ws.next().await {
+            Some(Ok(msg)) => match msg.inner {
+                protocol::Message::Text(text) => events.on_text(&mut ctx, text).await,
+                protocol::Message::Binary(data) => events.on_binary(&mut ctx, data).await,
+                protocol::Message::Ping(_) | protocol::Message::Pong(_) | protocol::Message::Frame(_) => {}
+                protocol::Message::Close(frame) => {
+                    let (code, reason) = match frame {
+                        Some(frame) => (Some(frame.code), frame.reason.as_str().to_owned()),
+                        None => (None, String::new()),
+                    };
+                    events.on_close(&mut ctx, code, reason).await;
+                    break;
+                }
+            },
+            Some(Err(err)) => {
+                events.on_error(&mut ctx, err).await;
+                break;
+            }
+            None => {
+                events.on_close(&mut ctx, None, String::new()).await;
+                break;
+            }
+        }
+    }
+
+    // Let the closing handshake complete, if any.
+    let _ = ctx.ws.flush().await;
+}
+
+/// Upgrades the http requests to websocket and drives the connections with the provided
+/// [`WebSocketEvents`](./trait.WebSocketEvents.html) hooks, with the provided config.
+///
+/// The config can either be an [`UpgradeConfig`](./struct.UpgradeConfig.html) or a plain [`WebSocketConfig`](./struct.WebSocketConfig.html).
+pub fn upgrade_ws_events_with_config<T, B, E, C>(
+    events: T,
+    config: C,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    T: WebSocketEvents,
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+    C: Into<UpgradeConfig>,
+{
+    let events = Arc::new(events);
+    upgrade_with(move |ws| drive(events.clone(), ws), config.into())
+}
+
+/// Upgrades the http requests to websocket and drives the connections with the provided
+/// [`WebSocketEvents`](./trait.WebSocketEvents.html) hooks.
+pub fn upgrade_ws_events<T, B, E>(
+    events: T,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    T: WebSocketEvents,
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    upgrade_ws_events_with_config(events, WebSocketConfig::default())
+}
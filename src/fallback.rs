@@ -0,0 +1,492 @@
+//! A fallback transport for the clients which can't upgrade to websocket, e.g. behind proxies stripping the `Upgrade`
+//! header.
+//!
+//! The routes built with [`upgrade_ws`](./fn.upgrade_ws.html) upgrade the websocket requests as usual, and serve the
+//! other clients over plain http requests: the server to client messages are streamed as
+//! [Server-Sent Events](https://html.spec.whatwg.org/multipage/server-sent-events.html), or long-polled by the clients
+//! which can't keep a response streaming either, and the client to server messages are posted. The handler receives
+//! the same [`WebSocket`](../struct.WebSocket.html) either way, with the same config, limits and rate limits.
+//!
+//! The fallback protocol:
+//!
+//! - A `GET` request with an `Accept: text/event-stream` header opens a streaming session. The first event is an
+//!   `open` event carrying the session id.
+//! - A `GET` request with a `transport=polling` query parameter opens a long-polling session. It is answered right
+//!   away with the `open` event. The next events are fetched by the `GET` requests with the session id in the
+//!   [`X-WebSocket-Session`](./constant.SESSION_HEADER.html) header, each one waiting until there is at least one
+//!   event and answered with all the events queued so far, in the same format as the streamed ones. A session which
+//!   is not polled for `30` seconds is closed.
+//! - The `Text` messages are sent as unnamed events, the line breaks being normalized to `\n`. The `Binary` messages
+//!   are sent as `binary` events carrying the base64 encoded data, and the `Close` message as a `close` event carrying
+//!   the code and the reason, after which the session ends. The `Ping` and `Pong` messages are not sent, comments are
+//!   sent every `15` seconds instead to keep the stream alive and to end the pending polls.
+//! - A `POST` request with the session id in the [`X-WebSocket-Session`](./constant.SESSION_HEADER.html) header sends
+//!   its body as a message, a `Binary` one if the content type is `application/octet-stream` and a `Text` one
+//!   otherwise. It is answered with `204 No Content` once the message is queued.
+//! - A `DELETE` request with the session id header closes the session.
+//!
+//! The [guards](../struct.UpgradeConfig.html#method.guard) run on every request of a session, not only on the one
+//! opening it, so a session can't be used by a client which is not allowed to open one.
+//!
+//! # Optional
+//!
+//! This requires the optional `fallback` feature to be enabled.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use futures::{SinkExt, StreamExt};
+//! use hyper::Body;
+//! use routerify::Router;
+//! use routerify_websocket::{fallback, Message, WebSocket};
+//! # use std::convert::Infallible;
+//!
+//! async fn ws_handler(mut ws: WebSocket) {
+//!     // The handler doesn't know which transport the connection uses.
+//!     while let Some(Ok(msg)) = ws.next().await {
+//!         if msg.is_text() {
+//!             ws.send(msg).await.unwrap();
+//!         }
+//!     }
+//! }
+//!
+//! fn router() -> Router<Body, Infallible> {
+//!     Router::builder()
+//!         .any_method("/ws", fallback::upgrade_ws(ws_handler))
+//!         .build()
+//!         .unwrap()
+//! }
+//! ```
+
+use crate::upgrade::{admit, is_upgrade_request, run_guards, upgrade_with};
+use crate::websocket::Transport;
+use crate::{UpgradeConfig, WebSocket, WebSocketConfig};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::channel::mpsc;
+use futures::future::ready;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Interval};
+use tokio_tungstenite::tungstenite::{protocol, Error};
+
+/// The header carrying the session id of the fallback requests.
+pub const SESSION_HEADER: &str = "x-websocket-session";
+
+/// The future returned by the fallback route handlers.
+pub type RouteFuture<E> = Pin<Box<dyn Future<Output = Result<Response<Body>, E>> + Send>>;
+
+/// How often a comment is sent to keep the event stream alive.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// How long a long-polling session can go without being polled before it is closed.
+const POLL_EXPIRY: Duration = Duration::from_secs(30);
+
+/// How many messages are buffered in each direction.
+const BUFFER: usize = 16;
+
+/// An open session, receiving the posted messages.
+struct Session {
+    incoming: mpsc::Sender<protocol::Message>,
+    polling: Option<Arc<Polling>>,
+}
+
+/// The events of a long-polling session, waiting to be polled.
+struct Polling {
+    events: tokio::sync::Mutex<mpsc::Receiver<Bytes>>,
+    last_poll: Mutex<Instant>,
+}
+
+impl Polling {
+    /// Returns true if the session is not being polled and was not polled recently.
+    fn expired(&self) -> bool {
+        self.events.try_lock().is_ok() && self.last_poll.lock().unwrap().elapsed() >= POLL_EXPIRY
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+/// Removes a session from the open sessions when its stream is dropped.
+struct SessionGuard {
+    id: String,
+    sessions: Sessions,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The messages of a fallback session, received from the posted requests and sent to the event stream.
+pub(crate) struct FallbackStream {
+    incoming: mpsc::Receiver<protocol::Message>,
+    events: mpsc::Sender<Bytes>,
+    keep_alive: Interval,
+    polling: Option<Arc<Polling>>,
+    closed: bool,
+    _session: SessionGuard,
+}
+
+impl FallbackStream {
+    fn finish(&mut self) {
+        self.closed = true;
+        self.events.close_channel();
+    }
+}
+
+impl Stream for FallbackStream {
+    type Item = Result<protocol::Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // The event stream is dropped once the client goes away, which is noticed on the next keep-alive, and a
+        // long-polling client is gone once it stops polling.
+        while this.keep_alive.poll_tick(cx).is_ready() {
+            let _ = this.events.try_send(Bytes::from_static(b": keep-alive\n\n"));
+            if this.polling.as_ref().is_some_and(|polling| polling.expired()) {
+                this.finish();
+            }
+        }
+        if this.closed || this.events.is_closed() {
+            return Poll::Ready(None);
+        }
+
+        match this.incoming.poll_next_unpin(cx) {
+            Poll::Ready(Some(protocol::Message::Close(frame))) => {
+                // Acknowledge the close like the websocket transport does.
+                let _ = this.events.try_send(encode_close(frame.as_ref()));
+                this.finish();
+                Poll::Ready(Some(Ok(protocol::Message::Close(frame))))
+            }
+            Poll::Ready(Some(msg)) => Poll::Ready(Some(Ok(msg))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Sink<protocol::Message> for FallbackStream {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.closed {
+            return Poll::Ready(Err(Error::AlreadyClosed));
+        }
+        self.events.poll_ready(cx).map_err(|_| Error::ConnectionClosed)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: protocol::Message) -> Result<(), Self::Error> {
+        if self.closed {
+            return Err(Error::AlreadyClosed);
+        }
+
+        let event = match item {
+            protocol::Message::Text(text) => encode_event(None, &text),
+            protocol::Message::Binary(data) => encode_event(Some("binary"), &BASE64.encode(data)),
+            protocol::Message::Close(frame) => {
+                let event = encode_close(frame.as_ref());
+                let result = self.events.start_send(event).map_err(|_| Error::ConnectionClosed);
+                self.finish();
+                return result;
+            }
+            protocol::Message::Ping(_) | protocol::Message::Pong(_) => return Ok(()),
+            // The streamed messages are buffered before being sent over this transport, and the raw frames refused by
+            // the websocket.
+            protocol::Message::Frame(_) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "The raw frames are not supported by the fallback transport",
+                )))
+            }
+        };
+
+        self.events.start_send(event).map_err(|_| Error::ConnectionClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.finish();
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn encode_event(name: Option<&str>, data: &str) -> Bytes {
+    let mut out = String::with_capacity(data.len() + 16);
+    if let Some(name) = name {
+        out.push_str("event: ");
+        out.push_str(name);
+        out.push('\n');
+    }
+    for line in data.replace("\r\n", "\n").split(['\n', '\r']) {
+        out.push_str("data: ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push('\n');
+
+    Bytes::from(out)
+}
+
+fn encode_close(frame: Option<&protocol::CloseFrame>) -> Bytes {
+    let data = match frame {
+        Some(frame) if frame.reason.is_empty() => u16::from(frame.code).to_string(),
+        Some(frame) => format!("{} {}", u16::from(frame.code), frame.reason),
+        None => String::new(),
+    };
+    encode_event(Some("close"), &data)
+}
+
+/// Opens a fallback session and spawns the handler with it, a long-polling one if `polling` is true.
+fn open<H, R>(
+    handler: H,
+    config: &UpgradeConfig,
+    sessions: &Sessions,
+    req: Request<Body>,
+    polling: bool,
+) -> Response<Body>
+where
+    H: Fn(WebSocket) -> R + Send + 'static,
+    R: Future<Output = ()> + Send + 'static,
+{
+    let mut admission = match admit(&req, config) {
+        Ok(admission) => admission,
+        Err(resp) => return resp,
+    };
+    admission.adopt_extensions(req);
+
+    let id = format!("{:032x}", rand::random::<u128>());
+    let open = encode_event(Some("open"), &id);
+    let (incoming_tx, incoming) = mpsc::channel(BUFFER);
+    let (events, events_rx) = mpsc::channel(BUFFER);
+
+    let (body, polling) = if polling {
+        let polling = Arc::new(Polling {
+            events: tokio::sync::Mutex::new(events_rx),
+            last_poll: Mutex::new(Instant::now()),
+        });
+        (Body::from(open), Some(polling))
+    } else {
+        let open = futures::stream::once(ready(open));
+        (Body::wrap_stream(open.chain(events_rx).map(Ok::<_, Infallible>)), None)
+    };
+
+    sessions.lock().unwrap().insert(
+        id.clone(),
+        Session {
+            incoming: incoming_tx,
+            polling: polling.clone(),
+        },
+    );
+
+    let mut builder = event_response().header(SESSION_HEADER, id.as_str());
+    if let Some(ref subprotocol) = admission.subprotocol {
+        builder = builder.header(header::SEC_WEBSOCKET_PROTOCOL, subprotocol.as_str());
+    }
+
+    let stream = FallbackStream {
+        incoming,
+        events,
+        keep_alive: tokio::time::interval_at(Instant::now() + KEEP_ALIVE, KEEP_ALIVE),
+        polling,
+        closed: false,
+        _session: SessionGuard {
+            id,
+            sessions: sessions.clone(),
+        },
+    };
+
+    let span = admission.span.clone();
+    span.handshake();
+    let ws = WebSocket::from_transport(Transport::Fallback(stream), admission, config);
+    tokio::spawn(span.instrument(handler(ws)));
+
+    builder.body(body).unwrap()
+}
+
+fn event_response() -> hyper::http::response::Builder {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header("x-accel-buffering", "no")
+}
+
+/// Answers a poll of a long-polling session with the events queued, once there is at least one.
+async fn poll(sessions: Sessions, id: &str) -> Response<Body> {
+    let polling = sessions
+        .lock()
+        .unwrap()
+        .get(id)
+        .and_then(|session| session.polling.clone());
+    let polling = match polling {
+        Some(polling) => polling,
+        None => return status(StatusCode::NOT_FOUND, "NOT FOUND: Unknown websocket session"),
+    };
+    let mut events = match polling.events.try_lock() {
+        Ok(events) => events,
+        Err(_) => {
+            return status(
+                StatusCode::CONFLICT,
+                "CONFLICT: The websocket session is already being polled",
+            )
+        }
+    };
+
+    let mut body = match events.next().await {
+        Some(event) => event.to_vec(),
+        None => return status(StatusCode::NOT_FOUND, "NOT FOUND: Unknown websocket session"),
+    };
+    while let Ok(event) = events.try_recv() {
+        body.extend_from_slice(&event);
+    }
+    *polling.last_poll.lock().unwrap() = Instant::now();
+
+    event_response().body(body.into()).unwrap()
+}
+
+/// Queues the message posted to a fallback session.
+async fn receive(sessions: Sessions, id: &str, req: Request<Body>, max_size: Option<usize>) -> Response<Body> {
+    let sender = sessions.lock().unwrap().get(id).map(|session| session.incoming.clone());
+    let mut sender = match sender {
+        Some(sender) => sender,
+        None => return status(StatusCode::NOT_FOUND, "NOT FOUND: Unknown websocket session"),
+    };
+
+    let msg = if req.method() == Method::DELETE {
+        protocol::Message::Close(None)
+    } else {
+        let binary = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|val| val.as_bytes().starts_with(b"application/octet-stream"));
+
+        let mut body = req.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(_) => return status(StatusCode::BAD_REQUEST, "BAD REQUEST: Couldn't read the message"),
+            }
+            if max_size.is_some_and(|max_size| data.len() > max_size) {
+                return status(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "PAYLOAD TOO LARGE: The message is too large",
+                );
+            }
+        }
+
+        if binary {
+            protocol::Message::Binary(data.into())
+        } else {
+            match String::from_utf8(data) {
+                Ok(text) => protocol::Message::Text(text.into()),
+                Err(_) => return status(StatusCode::BAD_REQUEST, "BAD REQUEST: The message is not UTF-8"),
+            }
+        }
+    };
+
+    match sender.send(msg).await {
+        Ok(()) => status(StatusCode::NO_CONTENT, ""),
+        Err(_) => status(StatusCode::NOT_FOUND, "NOT FOUND: Unknown websocket session"),
+    }
+}
+
+fn status(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder().status(status).body(body.into()).unwrap()
+}
+
+fn requests_polling(req: &Request<Body>) -> bool {
+    req.uri()
+        .query()
+        .is_some_and(|query| query.split('&').any(|param| param == "transport=polling"))
+}
+
+fn accepts_event_stream(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .any(|val| val.contains("text/event-stream"))
+}
+
+/// Upgrades the http requests to websocket, falling back to Server-Sent Events or long-polling and posted messages for
+/// the clients which can't upgrade, with the provided config.
+///
+/// The config can either be an [`UpgradeConfig`](../struct.UpgradeConfig.html) or a plain [`WebSocketConfig`](../struct.WebSocketConfig.html).
+/// The posted messages are limited to the `max_message_size` of the websocket config.
+pub fn upgrade_ws_with_config<H, R, E, C>(
+    handler: H,
+    config: C,
+) -> impl Fn(Request<Body>) -> RouteFuture<E> + Send + Sync + 'static
+where
+    H: Fn(WebSocket) -> R + Copy + Send + Sync + 'static,
+    R: Future<Output = ()> + Send + 'static,
+    E: std::error::Error + Send + 'static,
+    C: Into<UpgradeConfig>,
+{
+    let config = config.into();
+    let sessions = Sessions::default();
+    let upgrade = upgrade_with(handler, config.clone());
+
+    move |req: Request<Body>| -> RouteFuture<E> {
+        if is_upgrade_request(&req) {
+            return Box::pin(upgrade(req));
+        }
+
+        let session = req
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|val| val.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        match (req.method(), session) {
+            (&Method::GET, None) if requests_polling(&req) => {
+                Box::pin(ready(Ok(open(handler, &config, &sessions, req, true))))
+            }
+            (&Method::GET, None) if accepts_event_stream(&req) => {
+                Box::pin(ready(Ok(open(handler, &config, &sessions, req, false))))
+            }
+            (&Method::GET, Some(id)) => {
+                if let Err(resp) = run_guards(&req, &config) {
+                    return Box::pin(ready(Ok(resp)));
+                }
+                let sessions = sessions.clone();
+                Box::pin(async move { Ok(poll(sessions, &id).await) })
+            }
+            (&Method::POST, Some(id)) | (&Method::DELETE, Some(id)) => {
+                if let Err(resp) = run_guards(&req, &config) {
+                    return Box::pin(ready(Ok(resp)));
+                }
+                let sessions = sessions.clone();
+                let max_size = config.ws_config.max_message_size;
+                Box::pin(async move { Ok(receive(sessions, &id, req, max_size).await) })
+            }
+            // Rejected as a plain non websocket request.
+            _ => Box::pin(upgrade(req)),
+        }
+    }
+}
+
+/// Upgrades the http requests to websocket, falling back to Server-Sent Events or long-polling and posted messages for
+/// the clients which can't upgrade.
+pub fn upgrade_ws<H, R, E>(handler: H) -> impl Fn(Request<Body>) -> RouteFuture<E> + Send + Sync + 'static
+where
+    H: Fn(WebSocket) -> R + Copy + Send + Sync + 'static,
+    R: Future<Output = ()> + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    upgrade_ws_with_config(handler, WebSocketConfig::default())
+}
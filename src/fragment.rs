@@ -0,0 +1,623 @@
+use crate::metrics::{self, Direction};
+use crate::websocket::{Item, WebSocket};
+use futures::{ready, Stream};
+use hyper::upgrade::Upgraded;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{
+    error::{CapacityError, ProtocolError},
+    protocol::frame::coding::Data,
+    Bytes, Error,
+};
+
+/// The size of the reads from the underlying connection while the fragmented messages are streamed.
+const READ_SIZE: usize = 64 * 1024;
+
+/// A piece of a fragmented message diverted from the frames read by the websocket protocol.
+pub(crate) enum Fragment {
+    Start { text: bool },
+    Chunk(Bytes),
+    End,
+}
+
+enum ParseState {
+    Header,
+    Passthrough {
+        remaining: u64,
+    },
+    Divert {
+        remaining: u64,
+        mask: [u8; 4],
+        offset: usize,
+        fin: bool,
+    },
+}
+
+/// Splits the payloads of the incoming fragmented messages out of the connection, so they are streamed as they
+/// arrive instead of being buffered by the websocket protocol. All the other frames pass through unchanged.
+pub(crate) struct FrameFilter<S> {
+    inner: S,
+    enabled: bool,
+    max_frame_size: Option<usize>,
+    input: Vec<u8>,
+    pos: usize,
+    state: ParseState,
+    streaming: bool,
+    events: VecDeque<Fragment>,
+}
+
+enum Progress {
+    Forwarded,
+    Diverted,
+    Parsed,
+    NeedInput,
+}
+
+impl<S> FrameFilter<S> {
+    pub(crate) fn new(inner: S, enabled: bool, max_frame_size: Option<usize>) -> Self {
+        FrameFilter {
+            inner,
+            enabled,
+            max_frame_size,
+            input: Vec::new(),
+            pos: 0,
+            state: ParseState::Header,
+            streaming: false,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Takes the fragments diverted since the last call.
+    pub(crate) fn take_fragments(&mut self) -> impl Iterator<Item = Fragment> + '_ {
+        self.events.drain(..)
+    }
+
+    fn process(&mut self, buf: &mut ReadBuf) -> io::Result<Progress> {
+        let available = &self.input[self.pos..];
+
+        match self.state {
+            ParseState::Passthrough { ref mut remaining } => {
+                let n = (*remaining).min(available.len() as u64).min(buf.remaining() as u64) as usize;
+                if n == 0 {
+                    return Ok(Progress::NeedInput);
+                }
+
+                buf.put_slice(&available[..n]);
+                self.pos += n;
+                *remaining -= n as u64;
+                if *remaining == 0 {
+                    self.state = ParseState::Header;
+                }
+                Ok(Progress::Forwarded)
+            }
+            ParseState::Divert {
+                ref mut remaining,
+                mask,
+                ref mut offset,
+                fin,
+            } => {
+                let n = (*remaining).min(available.len() as u64) as usize;
+                if n == 0 && *remaining > 0 {
+                    return Ok(Progress::NeedInput);
+                }
+
+                if n > 0 {
+                    let chunk = available[..n]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, byte)| byte ^ mask[(*offset + i) % 4])
+                        .collect::<Vec<u8>>();
+                    self.events.push_back(Fragment::Chunk(chunk.into()));
+                    self.pos += n;
+                    *offset += n;
+                    *remaining -= n as u64;
+                }
+
+                if *remaining == 0 {
+                    self.state = ParseState::Header;
+                    if fin {
+                        self.streaming = false;
+                        self.events.push_back(Fragment::End);
+                    }
+                }
+                Ok(Progress::Diverted)
+            }
+            ParseState::Header => {
+                let header = match parse_header(available) {
+                    Some(header) => header,
+                    None => return Ok(Progress::NeedInput),
+                };
+                header.check(self.max_frame_size)?;
+
+                let divert = match (header.opcode, header.mask) {
+                    (1 | 2, _) if self.streaming => {
+                        let data = if header.opcode == 1 { Data::Text } else { Data::Binary };
+                        return Err(invalid_data(ProtocolError::ExpectedFragment(data)));
+                    }
+                    (1 | 2, Some(mask)) if !header.fin => {
+                        self.streaming = true;
+                        self.events.push_back(Fragment::Start {
+                            text: header.opcode == 1,
+                        });
+                        Some(mask)
+                    }
+                    (0, Some(mask)) if self.streaming => Some(mask),
+                    _ => None,
+                };
+
+                self.state = match divert {
+                    Some(mask) => {
+                        self.pos += header.len;
+                        ParseState::Divert {
+                            remaining: header.payload_len,
+                            mask,
+                            offset: 0,
+                            fin: header.fin,
+                        }
+                    }
+                    None => ParseState::Passthrough {
+                        remaining: header.len as u64 + header.payload_len,
+                    },
+                };
+                Ok(Progress::Parsed)
+            }
+        }
+    }
+}
+
+struct Header {
+    fin: bool,
+    rsv: u8,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    len: usize,
+    payload_len: u64,
+}
+
+impl Header {
+    /// Applies the checks of RFC 6455 §5.2 the websocket protocol can't apply to the diverted frames, so every frame
+    /// of a client gets them.
+    fn check(&self, max_frame_size: Option<usize>) -> io::Result<()> {
+        if self.rsv != 0 {
+            return Err(invalid_data(ProtocolError::NonZeroReservedBits));
+        }
+        if self.mask.is_none() {
+            return Err(invalid_data(ProtocolError::UnmaskedFrameFromClient));
+        }
+
+        match self.opcode {
+            0..=2 => {}
+            3..=7 => return Err(invalid_data(ProtocolError::UnknownDataFrameType(self.opcode))),
+            8..=10 if !self.fin => return Err(invalid_data(ProtocolError::FragmentedControlFrame)),
+            8..=10 if self.payload_len > 125 => return Err(invalid_data(ProtocolError::ControlFrameTooBig)),
+            8..=10 => {}
+            _ => return Err(invalid_data(ProtocolError::UnknownControlFrameType(self.opcode))),
+        }
+
+        match max_frame_size {
+            Some(max_size) if self.payload_len > max_size as u64 => Err(invalid_data(CapacityError::MessageTooLong {
+                size: self.payload_len.min(usize::MAX as u64) as usize,
+                max_size,
+            })),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The frames violating the protocol are reported as `InvalidData` IO errors, as the websocket protocol reads them.
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn parse_header(data: &[u8]) -> Option<Header> {
+    if data.len() < 2 {
+        return None;
+    }
+
+    let (ext_len, payload_len) = match data[1] & 0x7f {
+        126 => (2, u16::from_be_bytes([*data.get(2)?, *data.get(3)?]) as u64),
+        127 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(data.get(2..10)?);
+            (8, u64::from_be_bytes(len))
+        }
+        len => (0, len as u64),
+    };
+
+    let mut len = 2 + ext_len;
+    let mask = if data[1] & 0x80 != 0 {
+        let mut mask = [0; 4];
+        mask.copy_from_slice(data.get(len..len + 4)?);
+        len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    Some(Header {
+        fin: data[0] & 0x80 != 0,
+        rsv: data[0] & 0x70,
+        opcode: data[0] & 0x0f,
+        mask,
+        len,
+        payload_len,
+    })
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FrameFilter<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let mut diverted = false;
+        loop {
+            match this.process(buf)? {
+                Progress::Forwarded => return Poll::Ready(Ok(())),
+                Progress::Diverted => {
+                    diverted = true;
+                    continue;
+                }
+                Progress::Parsed => continue,
+                Progress::NeedInput => {}
+            }
+
+            // Hand the diverted fragments over before reading further, so at most one read is held in memory.
+            if diverted {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            if this.pos == this.input.len() {
+                this.input.clear();
+                this.pos = 0;
+            } else if this.pos > 0 {
+                this.input.drain(..this.pos);
+                this.pos = 0;
+            }
+
+            let start = this.input.len();
+            this.input.resize(start + READ_SIZE, 0);
+            let mut read_buf = ReadBuf::new(&mut this.input[start..]);
+            let res = Pin::new(&mut this.inner).poll_read(cx, &mut read_buf);
+            let filled = read_buf.filled().len();
+            this.input.truncate(start + filled);
+
+            ready!(res)?;
+            if filled == 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FrameFilter<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// An incoming message returned by [`WebSocket::next_incoming`](./struct.WebSocket.html#method.next_incoming).
+#[derive(Debug)]
+pub enum Incoming<'a, S = Upgraded> {
+    /// A message received as a whole.
+    Message(crate::Message),
+    /// A fragmented message streamed as its frames arrive.
+    Stream(MessageStream<'a, S>),
+}
+
+/// The payload of an incoming fragmented message, streamed as its frames arrive.
+///
+/// It yields the payload in chunks of bytes and ends with the last frame of the message. The chunks of a `Text`
+/// message are valid `UTF-8`, although a chunk may end in the middle of a line or a word. The control messages
+/// received in the meantime are returned by the next call to `next_incoming`.
+///
+/// The chunks are charged to the bytes budget of the [rate limit](./struct.RateLimit.html) as they arrive. When a
+/// chunk exceeds the budget under the `Drop` or `Close` policy, the stream ends with a
+/// [`WebsocketError::RateLimitExceeded`](./enum.WebsocketError.html#variant.RateLimitExceeded) error and the rest of
+/// the message is skipped.
+///
+/// If it is dropped before the end, the rest of the message is skipped.
+pub struct MessageStream<'a, S = Upgraded> {
+    ws: &'a mut WebSocket<S>,
+    text: bool,
+    partial: Vec<u8>,
+    done: bool,
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> MessageStream<'a, S> {
+    pub(crate) fn new(ws: &'a mut WebSocket<S>, text: bool) -> Self {
+        MessageStream {
+            ws,
+            text,
+            partial: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Returns true if this is a `Text` message.
+    pub fn is_text(&self) -> bool {
+        self.text
+    }
+
+    /// Returns true if this is a `Binary` message.
+    pub fn is_binary(&self) -> bool {
+        !self.text
+    }
+
+    /// Returns the valid `UTF-8` part of the chunk, holding back a character split over the next chunk.
+    fn validate(&mut self, chunk: Bytes) -> Option<Bytes> {
+        let chunk = if self.partial.is_empty() {
+            chunk
+        } else {
+            let mut joined = std::mem::take(&mut self.partial);
+            joined.extend_from_slice(&chunk);
+            Bytes::from(joined)
+        };
+
+        match std::str::from_utf8(&chunk) {
+            Ok(_) => Some(chunk),
+            Err(err) if err.error_len().is_none() => {
+                self.partial.extend_from_slice(&chunk[err.valid_up_to()..]);
+                Some(chunk.slice(..err.valid_up_to()))
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn fail(&mut self, cx: &mut Context, err: Error) -> Poll<Option<crate::Result<Bytes>>> {
+        let err = self.ws.receive_failed(cx, err);
+        self.abort(err)
+    }
+
+    fn abort(&mut self, err: crate::WebsocketError) -> Poll<Option<crate::Result<Bytes>>> {
+        self.done = true;
+        self.ws.restore_deferred();
+        Poll::Ready(Some(Err(err)))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for MessageStream<'_, S> {
+    type Item = crate::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            if let Some(err) = this.ws.poll_expiry(cx) {
+                this.ws.discard_stream();
+                return this.abort(err);
+            }
+            ready!(this.ws.poll_read_delay(cx));
+
+            let item = match ready!(this.ws.poll_item(cx)) {
+                Some(Ok(item)) => item,
+                Some(Err(err)) => return this.fail(cx, err),
+                None => return this.fail(cx, Error::ConnectionClosed),
+            };
+
+            match item {
+                Item::Message(msg) => this.ws.defer(msg),
+                Item::Fragment(Fragment::Chunk(chunk)) => {
+                    metrics::fragment(Direction::In, this.text, chunk.len(), false);
+                    this.ws.span().fragment(Direction::In, this.text, chunk.len());
+                    if let Err(err) = this.ws.charge_chunk(cx, chunk.len()) {
+                        return this.abort(err);
+                    }
+
+                    let chunk = if this.text {
+                        match this.validate(chunk) {
+                            Some(chunk) => chunk,
+                            None => return this.fail(cx, Error::Utf8),
+                        }
+                    } else {
+                        chunk
+                    };
+                    if !chunk.is_empty() {
+                        return Poll::Ready(Some(Ok(chunk)));
+                    }
+                }
+                Item::Fragment(Fragment::End) => {
+                    this.done = true;
+                    if !this.partial.is_empty() {
+                        return this.fail(cx, Error::Utf8);
+                    }
+                    return Poll::Ready(None);
+                }
+                Item::Fragment(Fragment::Start { .. }) => {}
+            }
+        }
+    }
+}
+
+impl<S> Drop for MessageStream<'_, S> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.ws.discard_stream();
+        }
+    }
+}
+
+impl<S> fmt::Debug for MessageStream<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MessageStream").field("text", &self.text).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![first];
+        match payload.len() {
+            len if len < 126 => data.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                data.push(0x80 | 126);
+                data.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                data.push(0x80 | 127);
+                data.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        data.extend_from_slice(&MASK);
+        data.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        data
+    }
+
+    async fn filter(input: Vec<u8>, max_frame_size: Option<usize>) -> io::Result<(Vec<u8>, Vec<String>)> {
+        let mut filter = FrameFilter::new(&input[..], true, max_frame_size);
+        let mut forwarded = Vec::new();
+        filter.read_to_end(&mut forwarded).await?;
+
+        let events = filter
+            .take_fragments()
+            .map(|fragment| match fragment {
+                Fragment::Start { text } => format!("start text={}", text),
+                Fragment::Chunk(chunk) => format!("chunk {}", String::from_utf8_lossy(&chunk)),
+                Fragment::End => "end".to_owned(),
+            })
+            .collect();
+        Ok((forwarded, events))
+    }
+
+    async fn protocol_error(input: Vec<u8>) -> ProtocolError {
+        let err = filter(input, None).await.expect_err("the frame should be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        err.into_inner()
+            .unwrap()
+            .downcast::<ProtocolError>()
+            .map(|err| *err)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn diverts_fragmented_message() {
+        let ping = frame(0x89, b"ping");
+        let input = [frame(0x01, b"hel"), ping.clone(), frame(0x80, b"lo")].concat();
+
+        let (forwarded, events) = filter(input, None).await.unwrap();
+        assert_eq!(forwarded, ping);
+        assert_eq!(events, ["start text=true", "chunk hel", "chunk lo", "end"]);
+    }
+
+    #[tokio::test]
+    async fn diverts_extended_payload_length() {
+        let payload = "a".repeat(300);
+        let input = [frame(0x02, payload.as_bytes()), frame(0x80, b"")].concat();
+
+        let (forwarded, events) = filter(input, None).await.unwrap();
+        assert!(forwarded.is_empty());
+        assert_eq!(
+            events,
+            [
+                "start text=false".to_owned(),
+                format!("chunk {}", payload),
+                "end".to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_whole_messages_through() {
+        let input = [frame(0x81, b"hello"), frame(0x82, b"world"), frame(0x88, b"")].concat();
+
+        let (forwarded, events) = filter(input.clone(), None).await.unwrap();
+        assert_eq!(forwarded, input);
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn passes_everything_through_when_disabled() {
+        let input = [frame(0x01, b"hel"), frame(0xc0, b"lo")].concat();
+
+        let mut filter = FrameFilter::new(&input[..], false, Some(1));
+        let mut forwarded = Vec::new();
+        filter.read_to_end(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, input);
+        assert_eq!(filter.take_fragments().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_reserved_bits() {
+        let input = [frame(0x01, b"hel"), frame(0xc0, b"lo")].concat();
+        assert_eq!(protocol_error(input).await, ProtocolError::NonZeroReservedBits);
+    }
+
+    #[tokio::test]
+    async fn rejects_reserved_opcodes() {
+        assert_eq!(
+            protocol_error(frame(0x03, b"data")).await,
+            ProtocolError::UnknownDataFrameType(3)
+        );
+        assert_eq!(
+            protocol_error(frame(0x8b, b"")).await,
+            ProtocolError::UnknownControlFrameType(11)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_control_frames() {
+        let input = [frame(0x01, b"hel"), frame(0x89, &[0; 126])].concat();
+        assert_eq!(protocol_error(input).await, ProtocolError::ControlFrameTooBig);
+
+        let input = [frame(0x01, b"hel"), frame(0x09, b"ping")].concat();
+        assert_eq!(protocol_error(input).await, ProtocolError::FragmentedControlFrame);
+    }
+
+    #[tokio::test]
+    async fn rejects_unmasked_frames() {
+        let input = [frame(0x01, b"hel"), vec![0x80, 0x02, b'l', b'o']].concat();
+        assert_eq!(protocol_error(input).await, ProtocolError::UnmaskedFrameFromClient);
+    }
+
+    #[tokio::test]
+    async fn rejects_new_message_while_streaming() {
+        let input = [frame(0x01, b"hel"), frame(0x82, b"lo")].concat();
+        assert_eq!(
+            protocol_error(input).await,
+            ProtocolError::ExpectedFragment(Data::Binary)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_frames_over_max_frame_size() {
+        let input = [frame(0x01, b"hel"), frame(0x80, b"lo, world")].concat();
+
+        let err = filter(input, Some(4)).await.expect_err("the frame should be rejected");
+        let err = err.into_inner().unwrap().downcast::<CapacityError>().unwrap();
+        assert_eq!(*err, CapacityError::MessageTooLong { size: 9, max_size: 4 });
+    }
+}
@@ -0,0 +1,389 @@
+//! The [`graphql-transport-ws`](https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md) protocol adapter.
+//!
+//! It implements the protocol state machine on top of [`WebSocket`](../struct.WebSocket.html): the connection
+//! initialisation and acknowledgement, the `ping`/`pong` keep-alive, and the `subscribe`, `next`, `error` and
+//! `complete` operation messages. The operations themselves are executed by a user supplied
+//! [`GraphQLExecutor`](./trait.GraphQLExecutor.html), so it works with any GraphQL engine.
+//!
+//! The `graphql-transport-ws` subprotocol is required in the handshake.
+//!
+//! # Optional
+//!
+//! This requires the optional `graphql` feature to be enabled.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use hyper::Body;
+//! # use routerify::Router;
+//! use futures::stream;
+//! use routerify_websocket::graphql::{upgrade_graphql, GraphQLExecutor, GraphQLRequest, ResponseStream};
+//! use serde_json::{json, Value};
+//! # use std::convert::Infallible;
+//!
+//! struct Executor;
+//!
+//! impl GraphQLExecutor for Executor {
+//!     async fn execute(&self, request: GraphQLRequest) -> Result<ResponseStream, Vec<Value>> {
+//!         // Run the operation with your GraphQL engine, here it just echoes the query.
+//!         let result = json!({ "data": { "query": request.query } });
+//!         Ok(Box::pin(stream::once(async move { result })))
+//!     }
+//! }
+//!
+//! fn router() -> Router<Body, Infallible> {
+//!     Router::builder()
+//!         .any_method("/graphql", upgrade_graphql(Executor))
+//!         .build()
+//!         .unwrap()
+//! }
+//! ```
+
+use crate::upgrade::upgrade_with;
+use crate::{CloseCode, Message, UpgradeConfig, WebSocket, WebSocketConfig};
+use futures::future::{poll_fn, Ready};
+use futures::{SinkExt, Stream, StreamExt};
+use hyper::{body::HttpBody, Request, Response};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The name of the subprotocol.
+pub const PROTOCOL: &str = "graphql-transport-ws";
+
+/// How long the client has to send the `connection_init` message after the connection is opened.
+pub const CONNECTION_INIT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The number of results queued for the connection before the operations wait for the client to read them.
+const OUTGOING_CAPACITY: usize = 32;
+
+/// The stream of execution results of an operation, a single one for queries and mutations.
+pub type ResponseStream = Pin<Box<dyn Stream<Item = Value> + Send>>;
+
+/// A GraphQL operation requested by a `subscribe` message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphQLRequest {
+    /// The GraphQL document.
+    pub query: String,
+    /// The name of the operation to execute.
+    pub operation_name: Option<String>,
+    /// The values of the variables.
+    pub variables: Map<String, Value>,
+    /// The protocol extensions.
+    pub extensions: Map<String, Value>,
+    /// The payload of the `connection_init` message.
+    pub init_payload: Option<Value>,
+}
+
+/// Executes the GraphQL operations with a GraphQL engine.
+pub trait GraphQLExecutor: Send + Sync + 'static {
+    /// Called with the payload of the `connection_init` message.
+    ///
+    /// It returns the payload of the `connection_ack` message, or an error reason to reject the connection with the
+    /// `4403: Forbidden` close code. All the connections are accepted by default.
+    fn on_connect(&self, payload: Option<&Value>) -> impl Future<Output = Result<Option<Value>, String>> + Send {
+        let _ = payload;
+        async { Ok(None) }
+    }
+
+    /// Executes an operation, returning the stream of its execution results sent as `next` messages.
+    ///
+    /// The errors returned before the execution are sent as an `error` message. The results are pulled from the stream
+    /// as fast as the client reads them, a slow client holding back the operation.
+    fn execute(&self, request: GraphQLRequest) -> impl Future<Output = Result<ResponseStream, Vec<Value>>> + Send;
+}
+
+/// The messages sent to the client by the operation tasks, tagged with the generation of the operation that sent them.
+enum Outgoing {
+    Next(String, u64, Value),
+    Error(String, u64, Vec<Value>),
+    Complete(String, u64),
+}
+
+impl Outgoing {
+    fn operation(&self) -> (&str, u64) {
+        match self {
+            Outgoing::Next(id, generation, _)
+            | Outgoing::Error(id, generation, _)
+            | Outgoing::Complete(id, generation) => (id, *generation),
+        }
+    }
+}
+
+enum Event {
+    Incoming(Option<crate::Result<Message>>),
+    Outgoing(Outgoing),
+    InitTimeout,
+}
+
+/// Serves the `graphql-transport-ws` protocol on an upgraded websocket connection, until it is closed.
+pub async fn serve<T: GraphQLExecutor>(ws: WebSocket, executor: Arc<T>) -> crate::Result<()> {
+    let mut ws = ws;
+    let (tx, mut rx) = mpsc::channel::<Outgoing>(OUTGOING_CAPACITY);
+    let mut init_timeout = Some(Box::pin(tokio::time::sleep(CONNECTION_INIT_TIMEOUT)));
+    let mut init_payload = None;
+    let mut acknowledged = false;
+    let mut operations = Operations(HashMap::new());
+    let mut generation = 0;
+
+    let close = loop {
+        let event = poll_fn(|cx| {
+            if let Some(ref mut timeout) = init_timeout {
+                if timeout.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Event::InitTimeout);
+                }
+            }
+            if let Poll::Ready(Some(outgoing)) = rx.poll_recv(cx) {
+                return Poll::Ready(Event::Outgoing(outgoing));
+            }
+            ws.poll_next_unpin(cx).map(Event::Incoming)
+        })
+        .await;
+
+        let msg = match event {
+            Event::InitTimeout => break Some((4408, "Connection initialisation timeout".to_owned())),
+            Event::Outgoing(outgoing) => {
+                // The operation was completed by the client meanwhile, and its id possibly reused by a new one.
+                let (id, current) = outgoing.operation();
+                if operations.get(id).map(|operation| operation.generation) != Some(current) {
+                    continue;
+                }
+                let msg = match outgoing {
+                    Outgoing::Next(id, _, payload) => json!({ "id": id, "type": "next", "payload": payload }),
+                    Outgoing::Error(id, _, errors) => {
+                        operations.remove(&id);
+                        json!({ "id": id, "type": "error", "payload": errors })
+                    }
+                    Outgoing::Complete(id, _) => {
+                        operations.remove(&id);
+                        json!({ "id": id, "type": "complete" })
+                    }
+                };
+                ws.send(Message::json(&msg)?).await?;
+                continue;
+            }
+            Event::Incoming(Some(Ok(msg))) => msg,
+            Event::Incoming(Some(Err(err))) => return Err(err),
+            Event::Incoming(None) => break None,
+        };
+
+        if msg.is_ping() || msg.is_pong() || msg.is_close() {
+            continue;
+        }
+
+        let mut msg = match serde_json::from_slice::<Value>(msg.as_bytes()) {
+            Ok(Value::Object(msg)) => msg,
+            _ => break Some((4400, "Invalid message received".to_owned())),
+        };
+
+        match msg.get("type").and_then(Value::as_str) {
+            Some("connection_init") => {
+                if init_timeout.take().is_none() {
+                    break Some((4429, "Too many initialisation requests".to_owned()));
+                }
+                init_payload = msg.remove("payload").filter(|payload| !payload.is_null());
+
+                match executor.on_connect(init_payload.as_ref()).await {
+                    Ok(payload) => {
+                        let ack = match payload {
+                            Some(payload) => json!({ "type": "connection_ack", "payload": payload }),
+                            None => json!({ "type": "connection_ack" }),
+                        };
+                        ws.send(Message::json(&ack)?).await?;
+                        acknowledged = true;
+                    }
+                    Err(_) => break Some((4403, "Forbidden".to_owned())),
+                }
+            }
+            Some("ping") => {
+                let pong = match msg.remove("payload") {
+                    Some(payload) => json!({ "type": "pong", "payload": payload }),
+                    None => json!({ "type": "pong" }),
+                };
+                ws.send(Message::json(&pong)?).await?;
+            }
+            Some("pong") => {}
+            Some("subscribe") => {
+                if !acknowledged {
+                    break Some((4401, "Unauthorized".to_owned()));
+                }
+
+                let (id, request) = match parse_subscribe(&mut msg, &init_payload) {
+                    Some(subscribe) => subscribe,
+                    None => break Some((4400, "Invalid message received".to_owned())),
+                };
+                if operations.contains_key(&id) {
+                    break Some((4409, format!("Subscriber for {} already exists", id)));
+                }
+
+                generation += 1;
+                let task = tokio::spawn(execute(executor.clone(), id.clone(), generation, request, tx.clone()));
+                operations.insert(id, Operation { generation, task });
+            }
+            Some("complete") => {
+                if let Some(operation) = msg
+                    .get("id")
+                    .and_then(Value::as_str)
+                    .and_then(|id| operations.remove(id))
+                {
+                    operation.task.abort();
+                }
+            }
+            _ => break Some((4400, "Invalid message received".to_owned())),
+        }
+    };
+
+    drop(operations);
+
+    match close {
+        Some((code, reason)) => ws.close_with(CloseCode::from(code), reason).await,
+        None => Ok(()),
+    }
+}
+
+async fn execute<T: GraphQLExecutor>(
+    executor: Arc<T>,
+    id: String,
+    generation: u64,
+    request: GraphQLRequest,
+    tx: mpsc::Sender<Outgoing>,
+) {
+    match executor.execute(request).await {
+        Ok(mut results) => {
+            // The next result is only polled once the previous one is queued, so a slow client slows the operation down.
+            while let Some(result) = results.next().await {
+                if tx.send(Outgoing::Next(id.clone(), generation, result)).await.is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send(Outgoing::Complete(id, generation)).await;
+        }
+        Err(errors) => {
+            let _ = tx.send(Outgoing::Error(id, generation, errors)).await;
+        }
+    }
+}
+
+fn parse_subscribe(msg: &mut Map<String, Value>, init_payload: &Option<Value>) -> Option<(String, GraphQLRequest)> {
+    let id = match msg.remove("id") {
+        Some(Value::String(id)) => id,
+        _ => return None,
+    };
+    let mut payload = match msg.remove("payload") {
+        Some(Value::Object(payload)) => payload,
+        _ => return None,
+    };
+
+    let query = match payload.remove("query") {
+        Some(Value::String(query)) => query,
+        _ => return None,
+    };
+    let operation_name = match payload.remove("operationName") {
+        Some(Value::String(name)) => Some(name),
+        Some(Value::Null) | None => None,
+        _ => return None,
+    };
+    let variables = match payload.remove("variables") {
+        Some(Value::Object(variables)) => variables,
+        Some(Value::Null) | None => Map::new(),
+        _ => return None,
+    };
+    let extensions = match payload.remove("extensions") {
+        Some(Value::Object(extensions)) => extensions,
+        Some(Value::Null) | None => Map::new(),
+        _ => return None,
+    };
+
+    Some((
+        id,
+        GraphQLRequest {
+            query,
+            operation_name,
+            variables,
+            extensions,
+            init_payload: init_payload.clone(),
+        },
+    ))
+}
+
+/// A running operation, with the generation telling its results apart from those of a previous operation with the
+/// same id.
+struct Operation {
+    generation: u64,
+    task: JoinHandle<()>,
+}
+
+/// The running operations by id, aborted when the connection is done.
+struct Operations(HashMap<String, Operation>);
+
+impl Deref for Operations {
+    type Target = HashMap<String, Operation>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Operations {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for Operations {
+    fn drop(&mut self) {
+        for (_, operation) in self.0.drain() {
+            operation.task.abort();
+        }
+    }
+}
+
+/// Upgrades the http requests to websocket and serves the `graphql-transport-ws` protocol with the provided
+/// executor and config.
+///
+/// The config can either be an [`UpgradeConfig`](../struct.UpgradeConfig.html) or a plain [`WebSocketConfig`](../struct.WebSocketConfig.html).
+/// The `graphql-transport-ws` subprotocol is required, the subprotocols set on the config are replaced.
+pub fn upgrade_graphql_with_config<T, B, E, C>(
+    executor: T,
+    config: C,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    T: GraphQLExecutor,
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+    C: Into<UpgradeConfig>,
+{
+    let executor = Arc::new(executor);
+    let config = config.into().subprotocols([PROTOCOL]).require_subprotocol(true);
+
+    upgrade_with(
+        move |ws| {
+            let executor = executor.clone();
+            async move {
+                if let Err(err) = serve(ws, executor).await {
+                    log::error!("{}", err);
+                }
+            }
+        },
+        config,
+    )
+}
+
+/// Upgrades the http requests to websocket and serves the `graphql-transport-ws` protocol with the provided executor.
+pub fn upgrade_graphql<T, B, E>(
+    executor: T,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    T: GraphQLExecutor,
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    upgrade_graphql_with_config(executor, WebSocketConfig::default())
+}
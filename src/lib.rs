@@ -8,7 +8,7 @@
 //! use hyper::{Body, Response, Server};
 //! use routerify::{Router, RouterService};
 //! // Import websocket types.
-//! use routerify_websocket::{upgrade_ws, Message, WebSocket};
+//! use routerify_websocket::{upgrade_ws, Message, MessageData, WebSocket};
 //! use std::{convert::Infallible, net::SocketAddr};
 //!
 //! // A handler for websocket connections.
@@ -24,10 +24,10 @@
 //!         let msg = msg.unwrap();
 //!
 //!         // Check message type and take appropriate actions.
-//!         if msg.is_text() {
-//!             println!("{}", msg.into_text().unwrap());
-//!         } else if msg.is_binary() {
-//!             println!("{:?}", msg.into_bytes());
+//!         match msg.into_data() {
+//!             MessageData::Text(text) => println!("{}", text),
+//!             MessageData::Binary(data) => println!("{:?}", data),
+//!             _ => {}
 //!         }
 //!
 //!         // Send a text message.
@@ -68,15 +68,68 @@
 //!     }
 //! }
 //! ```
+//!
+//! # Optional Features
+//!
+//! - `fallback`: Serve the clients which can't upgrade over [Server-Sent Events](./fallback/index.html).
+//! - `http2`: Accept the websocket connections over HTTP/2 with the extended `CONNECT` method of
+//!   [RFC 8441](https://tools.ietf.org/html/rfc8441). The server must enable it with
+//!   [`http2_enable_connect_protocol`](https://docs.rs/hyper/0.14/hyper/server/struct.Builder.html#method.http2_enable_connect_protocol).
+//! - `json`: Encode and decode the messages as `JSON`, and the JSON-RPC 2.0 [request/response layer](./rpc/index.html).
+//! - `graphql`: Serve the `graphql-transport-ws` protocol with the [GraphQL adapter](./graphql/index.html).
+//! - `socketio`: Serve the `socket.io-client` clients with the [Socket.IO compatible endpoint](./socketio/index.html).
+//! - `stomp`: Serve the STOMP 1.2 protocol with an in-memory [broker](./stomp/index.html).
+//! - `serde`: Serialize and deserialize the [`Message`](./struct.Message.html), e.g. to record the messages.
+//! - `tungstenite`: Convert the [`Message`](./struct.Message.html) from and to the `tokio_tungstenite::tungstenite::Message`.
+//! - `metrics`: Report the connection, message and error [metrics](./metrics/index.html).
+//! - `tracing`: Open a [`tracing`](https://docs.rs/tracing) span per connection, with events for the handshake,
+//!   the messages, the close frames and the errors. The handler future is instrumented with that span, so the
+//!   events emitted by the handlers are correlated with the connection.
 
-pub use self::error::WebsocketError;
-pub use message::Message;
-pub use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, WebSocketConfig};
-pub use upgrade::{upgrade_ws, upgrade_ws_with_config};
+pub use self::error::{ErrorKind, WebsocketError};
+pub use config::UpgradeConfig;
+pub use events::{upgrade_ws_events, upgrade_ws_events_with_config, EventContext, WebSocketEvents};
+pub use fragment::{Incoming, MessageStream};
+pub use hyper::http::Extensions;
+pub use limit::ConnectionLimits;
+pub use message::{Message, MessageData, MessageKind};
+pub use middleware::{MiddlewareContext, MiddlewareFlow, WebSocketMiddleware};
+pub use proxy::{ForwardedHeader, TrustedProxies};
+pub use rate_limit::{RateLimit, RateLimitPolicy};
+pub use session::SessionExpiry;
+pub use split::{ReuniteError, WebSocketReader, WebSocketWriter};
+pub use tokio_tungstenite::tungstenite::error::{CapacityError, Error as TungsteniteError, ProtocolError};
+pub use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Role, WebSocketConfig};
+pub use tokio_tungstenite::tungstenite::{Bytes, Utf8Bytes};
+pub use upgrade::{upgrade_ws, upgrade_ws_or, upgrade_ws_or_with_config, upgrade_ws_with_config};
 pub use websocket::WebSocket;
 
+mod config;
 mod error;
+mod events;
+#[cfg(feature = "fallback")]
+pub mod fallback;
+mod fragment;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+mod limit;
 mod message;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(not(feature = "metrics"))]
+mod metrics;
+mod middleware;
+mod proxy;
+mod rate_limit;
+#[cfg(feature = "json")]
+pub mod rpc;
+mod session;
+#[cfg(feature = "socketio")]
+pub mod socketio;
+mod split;
+#[cfg(feature = "stomp")]
+pub mod stomp;
+mod trace;
 mod upgrade;
 mod websocket;
 
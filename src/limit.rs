@@ -0,0 +1,263 @@
+use hyper::{http::Extensions, Request, StatusCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+type KeyFn = dyn Fn(&Request<hyper::Body>, &Extensions) -> Option<String> + Send + Sync;
+
+/// Limits the number of concurrent websocket connections accepted on the upgrade path.
+///
+/// The limits are checked before the connection is upgraded. If a limit is exceeded, the request is rejected
+/// with `503 Service Unavailable` (total limit) or `429 Too Many Requests` (per IP and per key limits) along with a
+/// `Retry-After` header. A slot is released as soon as the corresponding [`WebSocket`](./struct.WebSocket.html) is dropped.
+///
+/// The counters are shared between the clones of a `ConnectionLimits`, so the same instance can be used on
+/// multiple routes to enforce a limit across all of them.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use hyper::Body;
+/// # use routerify::Router;
+/// use routerify_websocket::{upgrade_ws_with_config, ConnectionLimits, UpgradeConfig, WebSocket};
+/// # use std::convert::Infallible;
+///
+/// # async fn ws_handler(ws: WebSocket) {}
+/// fn router() -> Router<Body, Infallible> {
+///     let limits = ConnectionLimits::new()
+///         .max_connections(10_000)
+///         .max_per_ip(16)
+///         .max_per_key(4, |req, _extensions| {
+///             req.headers()
+///                 .get("x-user-id")
+///                 .and_then(|val| val.to_str().ok())
+///                 .map(|val| val.to_owned())
+///         });
+///
+///     Router::builder()
+///         .any_method("/ws", upgrade_ws_with_config(ws_handler, UpgradeConfig::new().limits(limits)))
+///         .build()
+///         .unwrap()
+/// }
+/// ```
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    max_connections: Option<usize>,
+    max_per_ip: Option<usize>,
+    ipv6_prefix_len: u8,
+    max_per_key: Option<usize>,
+    key_fn: Option<Arc<KeyFn>>,
+    retry_after: Duration,
+    state: Arc<Mutex<LimitState>>,
+}
+
+#[derive(Default)]
+struct LimitState {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_key: HashMap<String, usize>,
+}
+
+impl ConnectionLimits {
+    /// Creates a new `ConnectionLimits` without any limit set.
+    ///
+    /// The IPv6 addresses are grouped by their `/64` prefix and the `Retry-After` header defaults to `1` second.
+    pub fn new() -> Self {
+        ConnectionLimits {
+            max_connections: None,
+            max_per_ip: None,
+            ipv6_prefix_len: 64,
+            max_per_key: None,
+            key_fn: None,
+            retry_after: Duration::from_secs(1),
+            state: Arc::new(Mutex::new(LimitState::default())),
+        }
+    }
+
+    /// Sets the maximum number of live connections in total.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of live connections per client IP address.
+    ///
+    /// The client address is resolved through the [trusted proxies](./struct.TrustedProxies.html) if configured.
+    pub fn max_per_ip(mut self, max: usize) -> Self {
+        self.max_per_ip = Some(max);
+        self
+    }
+
+    /// Sets the prefix length used to group the IPv6 addresses for the per IP limit.
+    ///
+    /// Clients usually own a whole IPv6 subnet, so counting the individual addresses is not effective. Defaults to `64`.
+    pub fn ipv6_prefix_len(mut self, len: u8) -> Self {
+        self.ipv6_prefix_len = len.min(128);
+        self
+    }
+
+    /// Sets the maximum number of live connections per key.
+    ///
+    /// The key is computed by the provided hook, for example an authenticated user id. The hook receives the upgrade
+    /// request and the extensions inserted by the [guards](./struct.UpgradeConfig.html#method.guard), which run before
+    /// the limits are checked. The requests for which the hook returns `None` are not subject to this limit.
+    pub fn max_per_key<F>(mut self, max: usize, key_fn: F) -> Self
+    where
+        F: Fn(&Request<hyper::Body>, &Extensions) -> Option<String> + Send + Sync + 'static,
+    {
+        self.max_per_key = Some(max);
+        self.key_fn = Some(Arc::new(key_fn));
+        self
+    }
+
+    /// Sets the duration sent in the `Retry-After` header when a limit is exceeded.
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Returns the number of live connections.
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).total
+    }
+
+    pub(crate) fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs().max(1)
+    }
+
+    pub(crate) fn acquire(
+        &self,
+        req: &Request<hyper::Body>,
+        extensions: &Extensions,
+        ip: IpAddr,
+    ) -> Result<ConnectionPermit, LimitExceeded> {
+        let ip = group_ip(ip, self.ipv6_prefix_len);
+        let key = self.key_fn.as_ref().and_then(|key_fn| key_fn(req, extensions));
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(max) = self.max_connections {
+            if state.total >= max {
+                return Err(LimitExceeded::Total);
+            }
+        }
+
+        if let Some(max) = self.max_per_ip {
+            if state.per_ip.get(&ip).copied().unwrap_or(0) >= max {
+                return Err(LimitExceeded::PerIp);
+            }
+        }
+
+        if let (Some(max), Some(key)) = (self.max_per_key, key.as_ref()) {
+            if state.per_key.get(key).copied().unwrap_or(0) >= max {
+                return Err(LimitExceeded::PerKey);
+            }
+        }
+
+        state.total += 1;
+        *state.per_ip.entry(ip).or_insert(0) += 1;
+        if let Some(ref key) = key {
+            *state.per_key.entry(key.clone()).or_insert(0) += 1;
+        }
+
+        Ok(ConnectionPermit {
+            state: self.state.clone(),
+            ip,
+            key,
+        })
+    }
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits::new()
+    }
+}
+
+impl fmt::Debug for ConnectionLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectionLimits")
+            .field("max_connections", &self.max_connections)
+            .field("max_per_ip", &self.max_per_ip)
+            .field("ipv6_prefix_len", &self.ipv6_prefix_len)
+            .field("max_per_key", &self.max_per_key)
+            .field("retry_after", &self.retry_after)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LimitExceeded {
+    Total,
+    PerIp,
+    PerKey,
+}
+
+impl LimitExceeded {
+    pub(crate) fn status(self) -> StatusCode {
+        match self {
+            LimitExceeded::Total => StatusCode::SERVICE_UNAVAILABLE,
+            LimitExceeded::PerIp | LimitExceeded::PerKey => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    pub(crate) fn reason(self) -> &'static str {
+        match self {
+            LimitExceeded::Total => "limit_total",
+            LimitExceeded::PerIp => "limit_per_ip",
+            LimitExceeded::PerKey => "limit_per_key",
+        }
+    }
+
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            LimitExceeded::Total => "SERVICE UNAVAILABLE: Too many websocket connections",
+            LimitExceeded::PerIp => "TOO MANY REQUESTS: Too many websocket connections from this address",
+            LimitExceeded::PerKey => "TOO MANY REQUESTS: Too many websocket connections for this key",
+        }
+    }
+}
+
+/// A slot taken from the [`ConnectionLimits`](./struct.ConnectionLimits.html), released when dropped.
+pub(crate) struct ConnectionPermit {
+    state: Arc<Mutex<LimitState>>,
+    ip: IpAddr,
+    key: Option<String>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        state.total = state.total.saturating_sub(1);
+        decrement(&mut state.per_ip, &self.ip);
+        if let Some(ref key) = self.key {
+            decrement(&mut state.per_key, key);
+        }
+    }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(map: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = map.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+fn group_ip(ip: IpAddr, ipv6_prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return IpAddr::V4(ip);
+            }
+
+            let mask = u128::MAX.checked_shl(128 - ipv6_prefix_len as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
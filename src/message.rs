@@ -1,11 +1,82 @@
 use crate::CloseCode;
 #[cfg(feature = "json")]
 use serde::{de::DeserializeOwned, ser::Serialize};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serializer};
 use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::fmt;
-use tokio_tungstenite::tungstenite::protocol::{self, CloseFrame};
+use tokio_tungstenite::tungstenite::{
+    protocol::{self, frame::coding::Control, frame::coding::OpCode, CloseFrame},
+    Bytes, Utf8Bytes,
+};
+
+/// The kind of a WebSocket [`Message`](./struct.Message.html), returned by [`Message::kind`](./struct.Message.html#method.kind).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    /// A `Text` message.
+    Text,
+    /// A `Binary` message.
+    Binary,
+    /// A `Ping` message.
+    Ping,
+    /// A `Pong` message.
+    Pong,
+    /// A `Close` message.
+    Close,
+}
+
+/// The data of a WebSocket [`Message`](./struct.Message.html), returned by [`Message::into_data`](./struct.Message.html#method.into_data).
+///
+/// # Examples
+///
+/// ```
+/// use routerify_websocket::{Message, MessageData};
+///
+/// match Message::text("Hello world").into_data() {
+///     MessageData::Text(text) => println!("{}", text),
+///     MessageData::Binary(data) => println!("{:?}", data),
+///     MessageData::Ping(_) | MessageData::Pong(_) => {}
+///     MessageData::Close(frame) => println!("{:?}", frame),
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageData {
+    /// The text of a `Text` message.
+    Text(Utf8Bytes),
+    /// The data of a `Binary` message.
+    Binary(Bytes),
+    /// The payload of a `Ping` message.
+    Ping(Bytes),
+    /// The payload of a `Pong` message.
+    Pong(Bytes),
+    /// The close code and reason of a `Close` message, if any.
+    Close(Option<CloseFrame>),
+}
 
 /// A WebSocket message.
+///
+/// It converts from the `String`, `&str`, `Vec<u8>` and [`Bytes`](./struct.Bytes.html) types, which is also the way to
+/// convert from the messages of the other websocket libraries.
+///
+/// # Optional
+///
+/// With the optional `tungstenite` feature, it converts from and to the `tokio_tungstenite::tungstenite::Message`.
+///
+/// With the optional `serde` feature, it is serialized as a map with a single entry, the kind of the message and its
+/// data, e.g. `{"text": "Hello world"}` or `{"close": {"code": 1000, "reason": ""}}`.
+///
+/// # Examples
+///
+/// ```
+/// use routerify_websocket::Message;
+///
+/// let msg = Message::from("Hello world");
+/// assert_eq!(msg.as_text().unwrap(), "Hello world");
+///
+/// let msg = Message::from(vec![1, 2, 3]);
+/// assert!(msg.is_binary());
+/// ```
 #[derive(Eq, PartialEq, Clone)]
 pub struct Message {
     pub(crate) inner: protocol::Message,
@@ -13,9 +84,12 @@ pub struct Message {
 
 impl Message {
     /// Create a new `Text` WebSocket message from a stringable.
+    ///
+    /// The text is kept as a shared [`Utf8Bytes`](./struct.Utf8Bytes.html) string, so cloning the message doesn't copy it.
+    /// A `Utf8Bytes` string is turned into a message with `Message::from`.
     pub fn text<S: Into<String>>(s: S) -> Message {
         Message {
-            inner: protocol::Message::text(s),
+            inner: protocol::Message::text(s.into()),
         }
     }
 
@@ -34,7 +108,7 @@ impl Message {
     /// Create a new `Binary` WebSocket message.
     pub fn binary<V: Into<Vec<u8>>>(v: V) -> Message {
         Message {
-            inner: protocol::Message::binary(v),
+            inner: protocol::Message::binary(v.into()),
         }
     }
 
@@ -43,7 +117,7 @@ impl Message {
     /// The payload here must have a length less than 125 bytes.
     pub fn ping<V: Into<Vec<u8>>>(v: V) -> Message {
         Message {
-            inner: protocol::Message::Ping(v.into()),
+            inner: protocol::Message::Ping(Bytes::from(v.into())),
         }
     }
 
@@ -52,7 +126,7 @@ impl Message {
     /// The payload here must have a length less than 125 bytes.
     pub fn pong<V: Into<Vec<u8>>>(v: V) -> Message {
         Message {
-            inner: protocol::Message::Pong(v.into()),
+            inner: protocol::Message::Pong(Bytes::from(v.into())),
         }
     }
 
@@ -68,11 +142,28 @@ impl Message {
         Message {
             inner: protocol::Message::Close(Some(CloseFrame {
                 code,
-                reason: reason.into(),
+                reason: close_reason(reason.into()),
             })),
         }
     }
 
+    /// Returns the kind of this message.
+    pub fn kind(&self) -> MessageKind {
+        match self.inner {
+            protocol::Message::Text(_) => MessageKind::Text,
+            protocol::Message::Binary(_) => MessageKind::Binary,
+            protocol::Message::Ping(_) => MessageKind::Ping,
+            protocol::Message::Pong(_) => MessageKind::Pong,
+            protocol::Message::Close(_) => MessageKind::Close,
+            protocol::Message::Frame(ref frame) => match frame.header().opcode {
+                OpCode::Control(Control::Ping) => MessageKind::Ping,
+                OpCode::Control(Control::Pong) => MessageKind::Pong,
+                OpCode::Control(Control::Close) => MessageKind::Close,
+                _ => MessageKind::Binary,
+            },
+        }
+    }
+
     /// Returns true if this message is a `Text` message.
     pub fn is_text(&self) -> bool {
         self.inner.is_text()
@@ -126,6 +217,8 @@ impl Message {
     }
 
     /// Attempts to convert the message data as text in `UTF8` format.
+    ///
+    /// It never fails for a `Text` message, whose text is validated once when it is received or created.
     pub fn as_text(&self) -> crate::Result<&str> {
         self.inner
             .to_text()
@@ -133,25 +226,55 @@ impl Message {
     }
 
     /// Return the bytes of this message.
+    ///
+    /// It is the close reason for a `Close` message.
     pub fn as_bytes(&self) -> &[u8] {
         match self.inner {
             protocol::Message::Text(ref s) => s.as_bytes(),
             protocol::Message::Binary(ref v) => v,
             protocol::Message::Ping(ref v) => v,
             protocol::Message::Pong(ref v) => v,
-            protocol::Message::Close(_) => &[],
+            protocol::Message::Close(Some(ref frame)) => frame.reason.as_bytes(),
+            protocol::Message::Close(None) => &[],
+            protocol::Message::Frame(ref frame) => frame.payload(),
         }
     }
 
     /// Consumes the message and returns its data as bytes.
     pub fn into_bytes(self) -> Vec<u8> {
-        self.inner.into_data()
+        self.inner.into_data().into()
+    }
+
+    /// Consumes the WebSocket message and returns its data, to handle every kind of message with a single `match`.
+    pub fn into_data(self) -> MessageData {
+        match self.inner {
+            protocol::Message::Text(text) => MessageData::Text(text),
+            protocol::Message::Binary(data) => MessageData::Binary(data),
+            protocol::Message::Ping(data) => MessageData::Ping(data),
+            protocol::Message::Pong(data) => MessageData::Pong(data),
+            protocol::Message::Close(frame) => MessageData::Close(frame),
+            protocol::Message::Frame(frame) => match frame.header().opcode {
+                OpCode::Control(Control::Ping) => MessageData::Ping(frame.into_payload()),
+                OpCode::Control(Control::Pong) => MessageData::Pong(frame.into_payload()),
+                _ => MessageData::Binary(frame.into_payload()),
+            },
+        }
+    }
+
+    /// Consumes the WebSocket message and attempts to convert it to a shared [`Utf8Bytes`](./struct.Utf8Bytes.html) string.
+    ///
+    /// Unlike [`into_text`](#method.into_text), it doesn't copy the text of a `Text` message.
+    pub fn into_utf8_bytes(self) -> crate::Result<Utf8Bytes> {
+        self.inner
+            .into_text()
+            .map_err(|err| crate::WebsocketError::DecodeText(err.into()))
     }
 
     /// Consumes the WebSocket message and attempts to converts it to a `String`.
     pub fn into_text(self) -> crate::Result<String> {
         self.inner
             .into_text()
+            .map(|text| text.as_str().to_owned())
             .map_err(|err| crate::WebsocketError::DecodeText(err.into()))
     }
 
@@ -172,8 +295,154 @@ impl fmt::Debug for Message {
     }
 }
 
-impl Into<Vec<u8>> for Message {
-    fn into(self) -> Vec<u8> {
-        self.into_bytes()
+impl From<Message> for Vec<u8> {
+    fn from(msg: Message) -> Vec<u8> {
+        msg.into_bytes()
+    }
+}
+
+impl TryFrom<Message> for String {
+    type Error = crate::WebsocketError;
+
+    fn try_from(msg: Message) -> crate::Result<String> {
+        msg.into_text()
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Message {
+        Message::text(text)
+    }
+}
+
+impl From<Utf8Bytes> for Message {
+    fn from(text: Utf8Bytes) -> Message {
+        Message {
+            inner: protocol::Message::Text(text),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Message {
+        Message::binary(data)
+    }
+}
+
+impl From<Bytes> for Message {
+    fn from(data: Bytes) -> Message {
+        Message {
+            inner: protocol::Message::Binary(data),
+        }
+    }
+}
+
+/// # Optional
+///
+/// This requires the optional `tungstenite` feature to be enabled.
+#[cfg(feature = "tungstenite")]
+impl From<protocol::Message> for Message {
+    fn from(inner: protocol::Message) -> Message {
+        Message { inner }
+    }
+}
+
+/// # Optional
+///
+/// This requires the optional `tungstenite` feature to be enabled.
+#[cfg(feature = "tungstenite")]
+impl From<Message> for protocol::Message {
+    fn from(msg: Message) -> protocol::Message {
+        msg.inner
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SerializedMessage<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+    Ping(&'a [u8]),
+    Pong(&'a [u8]),
+    Close(Option<SerializedClose<'a>>),
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct SerializedClose<'a> {
+    code: u16,
+    reason: &'a str,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeserializedMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<DeserializedClose>),
+}
+
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct DeserializedClose {
+    code: u16,
+    reason: String,
+}
+
+/// # Optional
+///
+/// This requires the optional `serde` feature to be enabled.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let msg = match self.kind() {
+            MessageKind::Text => SerializedMessage::Text(self.as_text().map_err(serde::ser::Error::custom)?),
+            MessageKind::Binary => SerializedMessage::Binary(self.as_bytes()),
+            MessageKind::Ping => SerializedMessage::Ping(self.as_bytes()),
+            MessageKind::Pong => SerializedMessage::Pong(self.as_bytes()),
+            MessageKind::Close => SerializedMessage::Close(match self.inner {
+                protocol::Message::Close(Some(ref frame)) => Some(SerializedClose {
+                    code: frame.code.into(),
+                    reason: &frame.reason,
+                }),
+                _ => None,
+            }),
+        };
+        msg.serialize(serializer)
+    }
+}
+
+/// # Optional
+///
+/// This requires the optional `serde` feature to be enabled.
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match DeserializedMessage::deserialize(deserializer)? {
+            DeserializedMessage::Text(text) => Message::text(text),
+            DeserializedMessage::Binary(data) => Message::binary(data),
+            DeserializedMessage::Ping(data) => Message::ping(data),
+            DeserializedMessage::Pong(data) => Message::pong(data),
+            DeserializedMessage::Close(None) => Message::close(),
+            DeserializedMessage::Close(Some(frame)) => Message::close_with(frame.code.into(), frame.reason),
+        })
+    }
+}
+
+/// Converts a close reason without copying the static ones.
+pub(crate) fn close_reason(reason: Cow<'static, str>) -> Utf8Bytes {
+    match reason {
+        Cow::Borrowed(reason) => Utf8Bytes::from_static(reason),
+        Cow::Owned(reason) => reason.into(),
     }
 }
@@ -0,0 +1,319 @@
+//! Metrics for the websocket connections, messages and bytes.
+//!
+//! The metrics are reported through the [`metrics`](https://docs.rs/metrics) crate facade, so they are exported by
+//! any installed recorder. They are also kept in process and can be rendered in the Prometheus text format with
+//! [`render_prometheus`](./fn.render_prometheus.html), or served directly with the
+//! [`prometheus_handler`](./fn.prometheus_handler.html) route handler.
+//!
+//! The following metrics are reported:
+//!
+//! - `routerify_websocket_upgrades_total{result, reason}`: The upgrade requests accepted or rejected.
+//! - `routerify_websocket_connections`: The live websocket connections.
+//! - `routerify_websocket_messages_total{direction, type}`: The messages received (`in`) or sent (`out`) by type.
+//! - `routerify_websocket_bytes_total{direction, type}`: The payload bytes received or sent by message type.
+//! - `routerify_websocket_close_codes_total{direction, code}`: The close codes received or sent.
+//! - `routerify_websocket_errors_total{kind}`: The errors by [`WebsocketError`](../enum.WebsocketError.html) variant.
+//!
+//! # Optional
+//!
+//! This requires the optional `metrics` feature to be enabled.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # #[cfg(feature = "metrics")]
+//! # mod example {
+//! # use hyper::Body;
+//! # use routerify::Router;
+//! use routerify_websocket::{metrics::prometheus_handler, upgrade_ws, WebSocket};
+//! # use std::convert::Infallible;
+//!
+//! # async fn ws_handler(ws: WebSocket) {}
+//! fn router() -> Router<Body, Infallible> {
+//!     Router::builder()
+//!         .any_method("/ws", upgrade_ws(ws_handler))
+//!         // Expose the websocket metrics to Prometheus.
+//!         .get("/metrics", prometheus_handler)
+//!         .build()
+//!         .unwrap()
+//! }
+//! # }
+//! ```
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables, dead_code))]
+
+use crate::{CloseCode, WebsocketError};
+#[cfg(feature = "metrics")]
+use hyper::{header, Body, Request, Response};
+#[cfg(feature = "metrics")]
+use std::collections::BTreeMap;
+#[cfg(feature = "metrics")]
+use std::fmt::Write;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+#[cfg(feature = "metrics")]
+use std::sync::Mutex;
+use tokio_tungstenite::tungstenite::protocol;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+const TYPES: [&str; 5] = ["text", "binary", "ping", "pong", "close"];
+
+fn type_index(msg: &protocol::Message) -> usize {
+    match msg {
+        protocol::Message::Text(_) => 0,
+        protocol::Message::Binary(_) => 1,
+        protocol::Message::Ping(_) => 2,
+        protocol::Message::Pong(_) => 3,
+        protocol::Message::Close(_) => 4,
+        // The frames of the streamed messages are reported with `fragment`.
+        protocol::Message::Frame(_) => 1,
+    }
+}
+
+#[cfg(feature = "metrics")]
+struct Registry {
+    upgrades: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    connections: AtomicI64,
+    messages: [[AtomicU64; 5]; 2],
+    bytes: [[AtomicU64; 5]; 2],
+    close_codes: Mutex<BTreeMap<(Direction, u16), u64>>,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[cfg(feature = "metrics")]
+#[allow(clippy::declare_interior_mutable_const)]
+const ZEROS: [AtomicU64; 5] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+#[cfg(feature = "metrics")]
+static REGISTRY: Registry = Registry {
+    upgrades: Mutex::new(BTreeMap::new()),
+    connections: AtomicI64::new(0),
+    messages: [ZEROS, ZEROS],
+    bytes: [ZEROS, ZEROS],
+    close_codes: Mutex::new(BTreeMap::new()),
+    errors: Mutex::new(BTreeMap::new()),
+};
+
+pub(crate) fn upgrade_accepted() {
+    upgrade("accepted", "ok");
+}
+
+pub(crate) fn upgrade_rejected(reason: &'static str) {
+    upgrade("rejected", reason);
+}
+
+fn upgrade(result: &'static str, reason: &'static str) {
+    #[cfg(feature = "metrics")]
+    {
+        *REGISTRY.upgrades.lock().unwrap().entry((result, reason)).or_insert(0) += 1;
+        ::metrics::counter!("routerify_websocket_upgrades_total", "result" => result, "reason" => reason).increment(1);
+    }
+}
+
+pub(crate) fn message(direction: Direction, msg: &protocol::Message) {
+    #[cfg(feature = "metrics")]
+    {
+        let idx = type_index(msg);
+        let len = msg.len() as u64;
+
+        REGISTRY.messages[direction as usize][idx].fetch_add(1, Ordering::Relaxed);
+        REGISTRY.bytes[direction as usize][idx].fetch_add(len, Ordering::Relaxed);
+        ::metrics::counter!("routerify_websocket_messages_total", "direction" => direction.as_str(), "type" => TYPES[idx])
+            .increment(1);
+        ::metrics::counter!("routerify_websocket_bytes_total", "direction" => direction.as_str(), "type" => TYPES[idx])
+            .increment(len);
+
+        if let protocol::Message::Close(Some(ref frame)) = msg {
+            close_code(direction, frame.code);
+        }
+    }
+}
+
+/// Reports a frame of a streamed message, counting the message with its first frame.
+pub(crate) fn fragment(direction: Direction, text: bool, len: usize, first: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let idx = if text { 0 } else { 1 };
+        let len = len as u64;
+
+        if first {
+            REGISTRY.messages[direction as usize][idx].fetch_add(1, Ordering::Relaxed);
+            ::metrics::counter!("routerify_websocket_messages_total", "direction" => direction.as_str(), "type" => TYPES[idx])
+                .increment(1);
+        }
+        REGISTRY.bytes[direction as usize][idx].fetch_add(len, Ordering::Relaxed);
+        ::metrics::counter!("routerify_websocket_bytes_total", "direction" => direction.as_str(), "type" => TYPES[idx])
+            .increment(len);
+    }
+}
+
+pub(crate) fn close_code(direction: Direction, code: CloseCode) {
+    #[cfg(feature = "metrics")]
+    {
+        let code = u16::from(code);
+
+        *REGISTRY
+            .close_codes
+            .lock()
+            .unwrap()
+            .entry((direction, code))
+            .or_insert(0) += 1;
+        ::metrics::counter!("routerify_websocket_close_codes_total", "direction" => direction.as_str(), "code" => code.to_string())
+            .increment(1);
+    }
+}
+
+pub(crate) fn error(err: &WebsocketError) {
+    #[cfg(feature = "metrics")]
+    {
+        let kind = error_kind(err);
+
+        *REGISTRY.errors.lock().unwrap().entry(kind).or_insert(0) += 1;
+        ::metrics::counter!("routerify_websocket_errors_total", "kind" => kind).increment(1);
+    }
+}
+
+fn error_kind(err: &WebsocketError) -> &'static str {
+    match err {
+        WebsocketError::Upgrade(_) => "upgrade",
+        WebsocketError::MessageReceive(_) => "message_receive",
+        WebsocketError::ReadyStatus(_) => "ready_status",
+        WebsocketError::MessageSend(_) => "message_send",
+        WebsocketError::MessageFlush(_) => "message_flush",
+        WebsocketError::DecodeText(_) => "decode_text",
+        #[cfg(feature = "json")]
+        WebsocketError::DecodeJson(_) => "decode_json",
+        #[cfg(feature = "json")]
+        WebsocketError::EncodeJson(_) => "encode_json",
+        WebsocketError::WebSocketClose(_) => "websocket_close",
+        WebsocketError::RateLimitExceeded => "rate_limit_exceeded",
+        WebsocketError::ClosedByMiddleware(_) => "closed_by_middleware",
+        WebsocketError::SessionExpired(_) => "session_expired",
+        WebsocketError::UnsupportedMessage => "unsupported_message",
+    }
+}
+
+/// Tracks a live connection for as long as it is alive.
+pub(crate) struct ConnectionGauge(());
+
+impl ConnectionGauge {
+    pub(crate) fn new() -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            let live = REGISTRY.connections.fetch_add(1, Ordering::Relaxed) + 1;
+            ::metrics::gauge!("routerify_websocket_connections").set(live as f64);
+        }
+
+        ConnectionGauge(())
+    }
+}
+
+impl Drop for ConnectionGauge {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            let live = REGISTRY.connections.fetch_sub(1, Ordering::Relaxed) - 1;
+            ::metrics::gauge!("routerify_websocket_connections").set(live as f64);
+        }
+    }
+}
+
+/// Renders the websocket metrics in the Prometheus text exposition format.
+///
+/// # Optional
+///
+/// This requires the optional `metrics` feature to be enabled.
+#[cfg(feature = "metrics")]
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP routerify_websocket_upgrades_total The websocket upgrade requests.\n");
+    out.push_str("# TYPE routerify_websocket_upgrades_total counter\n");
+    for ((result, reason), count) in REGISTRY.upgrades.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "routerify_websocket_upgrades_total{{result=\"{}\",reason=\"{}\"}} {}",
+            result, reason, count
+        );
+    }
+
+    out.push_str("# HELP routerify_websocket_connections The live websocket connections.\n");
+    out.push_str("# TYPE routerify_websocket_connections gauge\n");
+    let _ = writeln!(
+        out,
+        "routerify_websocket_connections {}",
+        REGISTRY.connections.load(Ordering::Relaxed)
+    );
+
+    for (name, help, values) in [
+        ("messages", "The websocket messages.", &REGISTRY.messages),
+        ("bytes", "The websocket message payload bytes.", &REGISTRY.bytes),
+    ] {
+        let _ = writeln!(out, "# HELP routerify_websocket_{}_total {}", name, help);
+        let _ = writeln!(out, "# TYPE routerify_websocket_{}_total counter", name);
+        for direction in [Direction::In, Direction::Out] {
+            for (idx, ty) in TYPES.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "routerify_websocket_{}_total{{direction=\"{}\",type=\"{}\"}} {}",
+                    name,
+                    direction.as_str(),
+                    ty,
+                    values[direction as usize][idx].load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    out.push_str("# HELP routerify_websocket_close_codes_total The websocket close codes.\n");
+    out.push_str("# TYPE routerify_websocket_close_codes_total counter\n");
+    for ((direction, code), count) in REGISTRY.close_codes.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "routerify_websocket_close_codes_total{{direction=\"{}\",code=\"{}\"}} {}",
+            direction.as_str(),
+            code,
+            count
+        );
+    }
+
+    out.push_str("# HELP routerify_websocket_errors_total The websocket errors.\n");
+    out.push_str("# TYPE routerify_websocket_errors_total counter\n");
+    for (kind, count) in REGISTRY.errors.lock().unwrap().iter() {
+        let _ = writeln!(out, "routerify_websocket_errors_total{{kind=\"{}\"}} {}", kind, count);
+    }
+
+    out
+}
+
+/// A route handler serving the websocket metrics in the Prometheus text exposition format.
+///
+/// # Optional
+///
+/// This requires the optional `metrics` feature to be enabled.
+#[cfg(feature = "metrics")]
+pub async fn prometheus_handler<E>(_req: Request<Body>) -> Result<Response<Body>, E> {
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(render_prometheus()))
+        .unwrap())
+}
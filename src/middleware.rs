@@ -0,0 +1,177 @@
+use crate::{CloseCode, Message};
+use hyper::http::Extensions;
+use std::borrow::Cow;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// The message hooks of a websocket connection, the equivalent of the routerify `pre` and `post` middlewares for the
+/// messages.
+///
+/// The middlewares are registered with [`UpgradeConfig::middleware`](./struct.UpgradeConfig.html#method.middleware)
+/// and run inside the [`WebSocket`](./struct.WebSocket.html), so the cross-cutting concerns like logging, payload
+/// policing or decryption are implemented once instead of in every handler. Every hook has a pass-through default
+/// implementation and returns a [`MiddlewareFlow`](./enum.MiddlewareFlow.html) deciding what happens to the message.
+///
+/// The incoming messages go through the middlewares in the order they were registered, and the outgoing messages in
+/// the reverse order, so the first middleware is the nearest to the peer. The messages [streamed](./enum.Incoming.html)
+/// as their frames arrive or are sent don't go through the middlewares.
+///
+/// # Examples
+///
+/// ```
+/// use routerify_websocket::{
+///     CloseCode, Message, MiddlewareContext, MiddlewareFlow, UpgradeConfig, WebSocketMiddleware,
+/// };
+///
+/// struct MaxTextLen(usize);
+///
+/// impl WebSocketMiddleware for MaxTextLen {
+///     fn inbound(&self, ctx: &mut MiddlewareContext, msg: Message) -> MiddlewareFlow {
+///         if msg.is_text() && msg.len() > self.0 {
+///             println!("{} sent a text too long", ctx.remote_addr());
+///             return MiddlewareFlow::Close(CloseCode::Policy, "Text too long".into());
+///         }
+///         MiddlewareFlow::Continue(msg)
+///     }
+/// }
+///
+/// let config = UpgradeConfig::new().middleware(MaxTextLen(1024));
+/// ```
+///
+/// # Asynchronous lookups
+///
+/// The hooks run while the `WebSocket` is polled, so they are synchronous and must not block, e.g. on a database or an
+/// authorization service. The data they need is looked up ahead and kept in the
+/// [extensions](./struct.MiddlewareContext.html#method.extensions) of the connection, which hold the extensions of the
+/// upgrade request. An asynchronous lookup fits in a routerify `pre` middleware of the upgrade route:
+///
+/// ```
+/// use hyper::Body;
+/// use routerify::{Middleware, Router};
+/// use routerify_websocket::{
+///     upgrade_ws_with_config, CloseCode, Message, MiddlewareContext, MiddlewareFlow, UpgradeConfig, WebSocket,
+///     WebSocketMiddleware,
+/// };
+/// use std::convert::Infallible;
+///
+/// struct Permissions {
+///     can_publish: bool,
+/// }
+///
+/// async fn fetch_permissions(token: Option<&str>) -> Permissions {
+///     // Ask the authorization service.
+///     Permissions {
+///         can_publish: token.is_some(),
+///     }
+/// }
+///
+/// struct PublishPolicy;
+///
+/// impl WebSocketMiddleware for PublishPolicy {
+///     fn inbound(&self, ctx: &mut MiddlewareContext, msg: Message) -> MiddlewareFlow {
+///         match ctx.extensions().get::<Permissions>() {
+///             Some(permissions) if permissions.can_publish => MiddlewareFlow::Continue(msg),
+///             _ => MiddlewareFlow::Close(CloseCode::Policy, "Not allowed to publish".into()),
+///         }
+///     }
+/// }
+///
+/// async fn ws_handler(ws: WebSocket) {
+///     // Handle the connection.
+///     # let _ = ws;
+/// }
+///
+/// fn router() -> Router<Body, Infallible> {
+///     Router::builder()
+///         .middleware(Middleware::pre(|mut req| async move {
+///             let token = req.headers().get("x-token").and_then(|val| val.to_str().ok());
+///             let permissions = fetch_permissions(token).await;
+///             req.extensions_mut().insert(permissions);
+///             Ok(req)
+///         }))
+///         .any_method(
+///             "/ws",
+///             upgrade_ws_with_config(ws_handler, UpgradeConfig::new().middleware(PublishPolicy)),
+///         )
+///         .build()
+///         .unwrap()
+/// }
+/// ```
+pub trait WebSocketMiddleware: Send + Sync + 'static {
+    /// Called with every message received from the peer, including the `Ping`, `Pong` and `Close` messages, before it is
+    /// returned by the [`WebSocket`](./struct.WebSocket.html) stream.
+    fn inbound(&self, ctx: &mut MiddlewareContext, msg: Message) -> MiddlewareFlow {
+        let _ = ctx;
+        MiddlewareFlow::Continue(msg)
+    }
+
+    /// Called with every message sent through the `Sink` implementation of the
+    /// [`WebSocket`](./struct.WebSocket.html), before it is sent to the peer.
+    fn outbound(&self, ctx: &mut MiddlewareContext, msg: Message) -> MiddlewareFlow {
+        let _ = ctx;
+        MiddlewareFlow::Continue(msg)
+    }
+}
+
+/// What happens to a message passed to a [`WebSocketMiddleware`](./trait.WebSocketMiddleware.html).
+#[derive(Debug)]
+pub enum MiddlewareFlow {
+    /// Passes the message, possibly transformed, to the next middleware.
+    Continue(Message),
+
+    /// Silently drops the message.
+    Drop,
+
+    /// Drops the message and closes the connection with a code and reason.
+    ///
+    /// An incoming message is replaced by a [`WebsocketError::ClosedByMiddleware`](./enum.WebsocketError.html#variant.ClosedByMiddleware)
+    /// error, and an outgoing message by the `Close` message.
+    Close(CloseCode, Cow<'static, str>),
+}
+
+/// The connection metadata passed to the [`WebSocketMiddleware`](./trait.WebSocketMiddleware.html) hooks.
+#[derive(Debug)]
+pub struct MiddlewareContext<'a> {
+    pub(crate) remote_addr: SocketAddr,
+    pub(crate) client_addr: SocketAddr,
+    pub(crate) subprotocol: Option<&'a str>,
+    pub(crate) extensions: &'a mut Extensions,
+}
+
+impl MiddlewareContext<'_> {
+    /// Get the peer's remote address, see [`WebSocket::remote_addr`](./struct.WebSocket.html#method.remote_addr).
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Get the client address, see [`WebSocket::client_addr`](./struct.WebSocket.html#method.client_addr).
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    /// Get the subprotocol selected during the handshake, if any.
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol
+    }
+
+    /// Get the [extensions](./struct.WebSocket.html#method.extensions) of the connection.
+    pub fn extensions(&self) -> &Extensions {
+        self.extensions
+    }
+
+    /// Get a mutable reference to the extensions of the connection.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        self.extensions
+    }
+}
+
+/// A middleware registered on the [`UpgradeConfig`](./struct.UpgradeConfig.html).
+#[derive(Clone)]
+pub(crate) struct Middleware(pub(crate) Arc<dyn WebSocketMiddleware>);
+
+impl fmt::Debug for Middleware {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Middleware").finish()
+    }
+}
@@ -0,0 +1,390 @@
+use hyper::{header, HeaderMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The set of proxies trusted to report the client address of a websocket connection.
+///
+/// When the TCP peer of an upgrade request is a trusted proxy, the client address is resolved from the
+/// [header](#method.header) the proxies write, `X-Forwarded-For` by default. Only that header is read, so a client
+/// can't inject another one the proxies pass on untouched. The forwarded chain is walked from the nearest hop and the
+/// first address which is not a trusted proxy is taken as the client, so the entries added by untrusted hops are never
+/// honoured.
+///
+/// The resolved address is available as [`WebSocket::client_addr`](./struct.WebSocket.html#method.client_addr).
+///
+/// # Examples
+///
+/// ```
+/// use routerify_websocket::{ForwardedHeader, TrustedProxies, UpgradeConfig};
+/// use std::net::{IpAddr, Ipv4Addr};
+///
+/// let proxies = TrustedProxies::new()
+///     .header(ForwardedHeader::Forwarded)
+///     .trust(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10)))
+///     .trust_network(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8);
+///
+/// let config = UpgradeConfig::new().trusted_proxies(proxies);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+    header: ForwardedHeader,
+}
+
+/// The header the [trusted proxies](./struct.TrustedProxies.html) report the client address in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardedHeader {
+    /// The standard [`Forwarded`](https://tools.ietf.org/html/rfc7239) header, the address being in its `for`
+    /// parameter.
+    Forwarded,
+
+    /// The `X-Forwarded-For` header, a list of addresses appended by every proxy.
+    #[default]
+    XForwardedFor,
+
+    /// The `X-Real-IP` header, the single address of the client set by the proxy.
+    XRealIp,
+}
+
+impl TrustedProxies {
+    /// Creates a new `TrustedProxies` which trusts nobody.
+    pub fn new() -> Self {
+        TrustedProxies::default()
+    }
+
+    /// Sets the header the proxies report the client address in, `X-Forwarded-For` by default.
+    ///
+    /// It must be the header the proxies overwrite or append to, as the other ones are passed on from the client as is.
+    pub fn header(mut self, header: ForwardedHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Trusts a single proxy address.
+    pub fn trust(self, addr: IpAddr) -> Self {
+        let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        self.trust_network(addr, prefix_len)
+    }
+
+    /// Trusts all the proxies in a network given by its address and prefix length, e.g. `10.0.0.0/8`.
+    ///
+    /// An IPv4-mapped IPv6 network, e.g. `::ffff:10.0.0.0/104`, is trusted as the IPv4 network it maps, its prefix
+    /// length being clamped to at least `96`.
+    pub fn trust_network(mut self, addr: IpAddr, prefix_len: u8) -> Self {
+        let network = match addr {
+            IpAddr::V4(_) => (addr, prefix_len.min(32)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => (IpAddr::V4(ip), prefix_len.clamp(96, 128) - 96),
+                None => (addr, prefix_len.min(128)),
+            },
+        };
+        self.networks.push(network);
+        self
+    }
+
+    /// Trusts the loopback and the private networks (`127.0.0.0/8`, `10.0.0.0/8`, `172.16.0.0/12`, `192.168.0.0/16`,
+    /// `::1/128` and `fc00::/7`).
+    pub fn trust_private(self) -> Self {
+        self.trust_network(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8)
+            .trust_network(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8)
+            .trust_network(IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12)
+            .trust_network(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16)
+            .trust(IpAddr::V6(Ipv6Addr::LOCALHOST))
+            .trust_network(IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7)
+    }
+
+    /// Returns true if the address belongs to a trusted proxy.
+    pub fn is_trusted(&self, addr: IpAddr) -> bool {
+        let addr = canonical_ip(addr);
+
+        self.networks
+            .iter()
+            .any(|&(network, prefix_len)| match (network, addr) {
+                (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                    mask(u32::from(network) as u128, prefix_len + 96) == mask(u32::from(addr) as u128, prefix_len + 96)
+                }
+                (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                    mask(u128::from(network), prefix_len) == mask(u128::from(addr), prefix_len)
+                }
+                _ => false,
+            })
+    }
+
+    /// Resolves the client address of a request received from the `peer_addr` TCP peer.
+    pub(crate) fn resolve(&self, peer_addr: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        if !self.is_trusted(peer_addr.ip()) {
+            return peer_addr;
+        }
+
+        let chain = forwarded_chain(headers, self.header);
+        let mut client = peer_addr;
+
+        for hop in chain.iter().rev() {
+            match hop {
+                Some(addr) => {
+                    client = *addr;
+                    if !self.is_trusted(addr.ip()) {
+                        break;
+                    }
+                }
+                // An obfuscated or unknown hop, nothing beyond it can be resolved.
+                None => break,
+            }
+        }
+
+        client
+    }
+}
+
+fn mask(addr: u128, prefix_len: u8) -> u128 {
+    addr & u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+}
+
+fn canonical_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Collects the forwarded hops, the client first, from the header.
+fn forwarded_chain(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<SocketAddr>> {
+    match header {
+        ForwardedHeader::Forwarded => header_values(headers, header::FORWARDED.as_str())
+            .flat_map(|val| val.split(',').map(str::to_owned).collect::<Vec<_>>())
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| {
+                        let mut parts = pair.splitn(2, '=');
+                        match (parts.next(), parts.next()) {
+                            (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("for") => Some(value),
+                            _ => None,
+                        }
+                    })
+                    .next()
+                    .and_then(|value| parse_node(value.trim().trim_matches('"')))
+            })
+            .collect(),
+        ForwardedHeader::XForwardedFor => header_values(headers, "x-forwarded-for")
+            .flat_map(|val| val.split(',').map(str::to_owned).collect::<Vec<_>>())
+            .map(|node| parse_node(node.trim()))
+            .collect(),
+        ForwardedHeader::XRealIp => header_values(headers, "x-real-ip")
+            .take(1)
+            .map(|node| parse_node(node.trim()))
+            .collect(),
+    }
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .map(|val| val.to_str().unwrap_or("unknown"))
+}
+
+/// Parses a node like `192.0.2.43`, `192.0.2.43:47011`, `2001:db8::1` or `[2001:db8::1]:4711`.
+///
+/// The port is `0` if the node doesn't contain one.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+
+    node.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn headers(entries: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in entries {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn trust_single_address() {
+        let proxies = TrustedProxies::new().trust(ip("192.0.2.10")).trust(ip("2001:db8::1"));
+
+        assert!(proxies.is_trusted(ip("192.0.2.10")));
+        assert!(proxies.is_trusted(ip("::ffff:192.0.2.10")));
+        assert!(!proxies.is_trusted(ip("192.0.2.11")));
+        assert!(proxies.is_trusted(ip("2001:db8::1")));
+        assert!(!proxies.is_trusted(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn trust_ipv4_mapped_address() {
+        let proxies = TrustedProxies::new().trust(ip("::ffff:10.0.0.1"));
+
+        assert!(proxies.is_trusted(ip("10.0.0.1")));
+        assert!(proxies.is_trusted(ip("::ffff:10.0.0.1")));
+        assert!(!proxies.is_trusted(ip("10.0.0.2")));
+        assert!(!proxies.is_trusted(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn trust_ipv4_mapped_network() {
+        let proxies = TrustedProxies::new().trust_network(ip("::ffff:10.0.0.0"), 104);
+
+        assert!(proxies.is_trusted(ip("10.255.0.1")));
+        assert!(!proxies.is_trusted(ip("11.0.0.1")));
+
+        let proxies = TrustedProxies::new().trust_network(ip("::ffff:0.0.0.0"), 80);
+        assert!(proxies.is_trusted(ip("203.0.113.7")));
+        assert!(!proxies.is_trusted(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn cidr_prefixes() {
+        let proxies = TrustedProxies::new()
+            .trust_network(ip("172.16.0.0"), 12)
+            .trust_network(ip("2001:db8:aa00::"), 40);
+
+        assert!(proxies.is_trusted(ip("172.16.0.1")));
+        assert!(proxies.is_trusted(ip("172.31.255.255")));
+        assert!(!proxies.is_trusted(ip("172.32.0.0")));
+        assert!(proxies.is_trusted(ip("2001:db8:aaff::1")));
+        assert!(!proxies.is_trusted(ip("2001:db8:ab00::1")));
+
+        let all = TrustedProxies::new()
+            .trust_network(ip("0.0.0.0"), 0)
+            .trust_network(ip("::"), 0);
+        assert!(all.is_trusted(ip("203.0.113.7")));
+        assert!(all.is_trusted(ip("2001:db8::1")));
+
+        let clamped = TrustedProxies::new().trust_network(ip("192.0.2.10"), 64);
+        assert!(clamped.is_trusted(ip("192.0.2.10")));
+        assert!(!clamped.is_trusted(ip("192.0.2.11")));
+    }
+
+    #[test]
+    fn trust_private() {
+        let proxies = TrustedProxies::new().trust_private();
+
+        for addr in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.20.0.1",
+            "192.168.1.1",
+            "::1",
+            "fd00::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(proxies.is_trusted(ip(addr)), "{}", addr);
+        }
+        for addr in &["203.0.113.7", "172.32.0.1", "2001:db8::1", "::2"] {
+            assert!(!proxies.is_trusted(ip(addr)), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn parse_nodes() {
+        assert_eq!(parse_node("192.0.2.43"), Some(addr("192.0.2.43:0")));
+        assert_eq!(parse_node("192.0.2.43:47011"), Some(addr("192.0.2.43:47011")));
+        assert_eq!(parse_node("2001:db8::1"), Some(addr("[2001:db8::1]:0")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(addr("[2001:db8::1]:0")));
+        assert_eq!(parse_node("[2001:db8::1]:4711"), Some(addr("[2001:db8::1]:4711")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn forwarded_chain_with_mixed_entries() {
+        let headers = headers(&[
+            (
+                "forwarded",
+                r#"for=192.0.2.43;proto=https, For="[2001:db8:cafe::17]:4711";by=203.0.113.60"#,
+            ),
+            ("forwarded", "proto=http;for=unknown, for=198.51.100.17:8080"),
+            ("x-forwarded-for", "203.0.113.99"),
+        ]);
+
+        assert_eq!(
+            forwarded_chain(&headers, ForwardedHeader::Forwarded),
+            vec![
+                Some(addr("192.0.2.43:0")),
+                Some(addr("[2001:db8:cafe::17]:4711")),
+                None,
+                Some(addr("198.51.100.17:8080")),
+            ]
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for_chain_with_mixed_entries() {
+        let headers = headers(&[
+            ("x-forwarded-for", "2001:db8::1, 192.0.2.43"),
+            ("x-forwarded-for", "[2001:db8::2]:80,garbage"),
+            ("x-real-ip", "203.0.113.99"),
+        ]);
+
+        assert_eq!(
+            forwarded_chain(&headers, ForwardedHeader::XForwardedFor),
+            vec![
+                Some(addr("[2001:db8::1]:0")),
+                Some(addr("192.0.2.43:0")),
+                Some(addr("[2001:db8::2]:80")),
+                None,
+            ]
+        );
+
+        let headers = self::headers(&[("x-real-ip", " 2001:db8::3 ")]);
+        assert_eq!(
+            forwarded_chain(&headers, ForwardedHeader::XRealIp),
+            vec![Some(addr("[2001:db8::3]:0"))]
+        );
+        assert!(forwarded_chain(&headers, ForwardedHeader::XForwardedFor).is_empty());
+    }
+
+    #[test]
+    fn resolve_client() {
+        let proxies = TrustedProxies::new()
+            .trust(ip("10.0.0.1"))
+            .trust_network(ip("2001:db8:ffff::"), 48);
+        let headers = headers(&[("x-forwarded-for", "198.51.100.1, 192.0.2.43, 2001:db8:ffff::5")]);
+
+        assert_eq!(proxies.resolve(addr("10.0.0.1:5000"), &headers), addr("192.0.2.43:0"));
+        assert_eq!(
+            proxies.resolve(addr("[::ffff:10.0.0.1]:5000"), &headers),
+            addr("192.0.2.43:0")
+        );
+        assert_eq!(proxies.resolve(addr("10.0.0.2:5000"), &headers), addr("10.0.0.2:5000"));
+
+        let proxies = proxies.header(ForwardedHeader::Forwarded);
+        let headers = self::headers(&[("forwarded", "for=192.0.2.1, for=unknown")]);
+        assert_eq!(proxies.resolve(addr("10.0.0.1:5000"), &headers), addr("10.0.0.1:5000"));
+    }
+
+    #[test]
+    fn ignore_headers_injected_by_the_client() {
+        let proxies = TrustedProxies::new().trust(ip("10.0.0.1"));
+        // The proxy appends to `X-Forwarded-For` and passes the `Forwarded` header sent by the client on.
+        let headers = headers(&[("forwarded", "for=1.2.3.4"), ("x-forwarded-for", "198.51.100.7")]);
+
+        assert_eq!(proxies.resolve(addr("10.0.0.1:5000"), &headers), addr("198.51.100.7:0"));
+
+        let proxies = proxies.header(ForwardedHeader::Forwarded);
+        assert_eq!(proxies.resolve(addr("10.0.0.1:5000"), &headers), addr("1.2.3.4:0"));
+
+        let proxies = proxies.header(ForwardedHeader::XRealIp);
+        assert_eq!(proxies.resolve(addr("10.0.0.1:5000"), &headers), addr("10.0.0.1:5000"));
+    }
+}
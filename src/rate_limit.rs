@@ -0,0 +1,248 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The action taken when a connection exceeds its [`RateLimit`](./struct.RateLimit.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Delays reading the next message from the connection until the budget is available again.
+    Delay,
+
+    /// Silently drops the messages exceeding the budget.
+    Drop,
+
+    /// Closes the connection with the [`CloseCode::Policy`](./enum.CloseCode.html#variant.Policy) code.
+    Close,
+}
+
+/// A token-bucket rate limit applied to the incoming messages of a websocket connection.
+///
+/// The `Close` messages are never limited. The limit is enforced inside the `Stream` implementation of
+/// [`WebSocket`](./struct.WebSocket.html), so the handlers keep reading the messages as usual.
+///
+/// # Examples
+///
+/// ```
+/// use routerify_websocket::{RateLimit, RateLimitPolicy, UpgradeConfig};
+///
+/// // Allow 20 messages and 64 KiB per second, with bursts up to 50 messages and 256 KiB.
+/// let rate_limit = RateLimit::new()
+///     .messages(20, 50)
+///     .bytes(64 * 1024, 256 * 1024)
+///     .policy(RateLimitPolicy::Close);
+///
+/// let config = UpgradeConfig::new().rate_limit(rate_limit);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    messages: Option<(u32, u32)>,
+    bytes: Option<(u64, u64)>,
+    policy: RateLimitPolicy,
+}
+
+impl RateLimit {
+    /// Creates a new `RateLimit` without any budget set, using the [`Delay`](./enum.RateLimitPolicy.html#variant.Delay) policy.
+    pub fn new() -> Self {
+        RateLimit {
+            messages: None,
+            bytes: None,
+            policy: RateLimitPolicy::Delay,
+        }
+    }
+
+    /// Limits the number of messages per second, allowing bursts up to `burst` messages.
+    pub fn messages(mut self, per_second: u32, burst: u32) -> Self {
+        self.messages = Some((per_second, burst.max(1)));
+        self
+    }
+
+    /// Limits the number of payload bytes per second, allowing bursts up to `burst` bytes.
+    ///
+    /// A message larger than `burst` is let through once the budget is full, and its bytes are all charged, so the next
+    /// messages are limited until they are paid back.
+    pub fn bytes(mut self, per_second: u64, burst: u64) -> Self {
+        self.bytes = Some((per_second, burst.max(1)));
+        self
+    }
+
+    /// Sets the action taken when the budget is exceeded.
+    pub fn policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit::new()
+    }
+}
+
+/// The outcome of checking an incoming message against the rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Verdict {
+    Allow,
+    DelayNext(Instant),
+    Drop,
+    Close,
+}
+
+pub(crate) struct RateLimiter {
+    policy: RateLimitPolicy,
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        let now = Instant::now();
+
+        RateLimiter {
+            policy: limit.policy,
+            messages: limit
+                .messages
+                .map(|(rate, burst)| TokenBucket::new(rate as f64, burst as f64, now)),
+            bytes: limit
+                .bytes
+                .map(|(rate, burst)| TokenBucket::new(rate as f64, burst as f64, now)),
+        }
+    }
+
+    pub(crate) fn check(&mut self, len: usize) -> Verdict {
+        self.charge(1.0, len as f64)
+    }
+
+    /// Checks a chunk of a streamed message, whose bytes are charged as they arrive. The message itself is counted by
+    /// [`check`](#method.check) when it starts.
+    pub(crate) fn check_chunk(&mut self, len: usize) -> Verdict {
+        self.charge(0.0, len as f64)
+    }
+
+    fn charge(&mut self, count: f64, len: f64) -> Verdict {
+        let now = Instant::now();
+
+        if let Some(ref mut bucket) = self.messages {
+            bucket.refill(now);
+        }
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.refill(now);
+        }
+
+        match self.policy {
+            RateLimitPolicy::Delay => {
+                let wait = [
+                    self.messages.as_mut().map(|bucket| bucket.consume(count)),
+                    self.bytes.as_mut().map(|bucket| bucket.consume(len)),
+                ]
+                .iter()
+                .flatten()
+                .fold(Duration::from_secs(0), |max, wait| max.max(*wait));
+
+                if wait > Duration::from_secs(0) {
+                    Verdict::DelayNext(now + wait)
+                } else {
+                    Verdict::Allow
+                }
+            }
+            RateLimitPolicy::Drop | RateLimitPolicy::Close => {
+                let allowed = self.messages.as_ref().map(|bucket| bucket.has(count)).unwrap_or(true)
+                    && self.bytes.as_ref().map(|bucket| bucket.has(len)).unwrap_or(true);
+
+                if allowed {
+                    if let Some(ref mut bucket) = self.messages {
+                        bucket.consume(count);
+                    }
+                    // A message larger than the burst is let through with a full bucket, and fully charged so the bucket
+                    // goes into debt and the next messages are throttled.
+                    if let Some(ref mut bucket) = self.bytes {
+                        bucket.consume(len);
+                    }
+                    Verdict::Allow
+                } else if self.policy == RateLimitPolicy::Drop {
+                    Verdict::Drop
+                } else {
+                    Verdict::Close
+                }
+            }
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Returns true if the tokens are available, or the bucket is full for an amount larger than its capacity.
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= amount.min(self.capacity)
+    }
+
+    /// Consumes the tokens, going into debt if needed, and returns how long it takes to pay the debt back.
+    fn consume(&mut self, amount: f64) -> Duration {
+        self.tokens -= amount;
+
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else if self.rate > 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::from_secs(u32::MAX as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charges_messages_larger_than_the_burst() {
+        let mut limiter = RateLimiter::new(RateLimit::new().bytes(10, 100).policy(RateLimitPolicy::Drop));
+
+        assert_eq!(limiter.check(1000), Verdict::Allow);
+        assert_eq!(limiter.check(1), Verdict::Drop);
+        assert_eq!(limiter.check_chunk(1), Verdict::Drop);
+    }
+
+    #[test]
+    fn delays_after_messages_larger_than_the_burst() {
+        let mut limiter = RateLimiter::new(RateLimit::new().bytes(100, 100));
+
+        match limiter.check(1000) {
+            Verdict::DelayNext(deadline) => {
+                let wait = deadline - Instant::now();
+                assert!(wait > Duration::from_secs(8) && wait <= Duration::from_secs(9));
+            }
+            verdict => panic!("unexpected verdict {:?}", verdict),
+        }
+    }
+
+    #[test]
+    fn closes_over_the_message_budget() {
+        let mut limiter = RateLimiter::new(RateLimit::new().messages(1, 2).policy(RateLimitPolicy::Close));
+
+        assert_eq!(limiter.check(0), Verdict::Allow);
+        assert_eq!(limiter.check(0), Verdict::Allow);
+        assert_eq!(limiter.check(0), Verdict::Close);
+        // The chunks of a streamed message only count its bytes.
+        assert_eq!(limiter.check_chunk(100), Verdict::Allow);
+    }
+}
@@ -0,0 +1,641 @@
+//! Request/response correlation over a websocket connection.
+//!
+//! The [`Rpc`](./struct.Rpc.html) layer takes over a [`WebSocket`](../struct.WebSocket.html): it dispatches the
+//! incoming requests to the registered [`RpcMethods`](./struct.RpcMethods.html) and sends their results back, while
+//! the [`RpcHandle`](./struct.RpcHandle.html) issues calls to the client, assigning the request ids and matching the
+//! responses by id.
+//!
+//! The messages are encoded with an [`RpcEnvelope`](./trait.RpcEnvelope.html), [`JsonRpc2`](./struct.JsonRpc2.html)
+//! being the default one.
+//!
+//! # Optional
+//!
+//! This requires the optional `json` feature to be enabled.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use routerify_websocket::rpc::{Rpc, RpcError, RpcMethods};
+//! use routerify_websocket::WebSocket;
+//! use serde_json::Value;
+//!
+//! async fn ws_handler(ws: WebSocket) {
+//!     let mut methods = RpcMethods::new();
+//!     methods.method("add", |(a, b): (i64, i64)| async move { Ok::<_, RpcError>(a + b) });
+//!
+//!     let rpc = Rpc::new(ws, methods);
+//!
+//!     // Call a method on the client while the connection is served.
+//!     let handle = rpc.handle();
+//!     tokio::spawn(async move {
+//!         let version: Result<Value, RpcError> = handle.call("client.version", ()).await;
+//!         println!("Client version: {:?}", version);
+//!     });
+//!
+//!     rpc.run().await;
+//! }
+//! ```
+
+use crate::{Message, WebSocket};
+use futures::future::poll_fn;
+use futures::{SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinError, JoinSet};
+
+/// The number of outgoing frames queued before the calls and notifications wait for the connection to send them.
+const OUTGOING_CAPACITY: usize = 64;
+
+/// A structured RPC error, sent back to the caller when a method fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    /// The error code.
+    pub code: i64,
+    /// A short description of the error.
+    pub message: String,
+    /// Additional information about the error.
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    /// Invalid JSON was received.
+    pub const PARSE_ERROR: i64 = -32700;
+    /// The message is not a valid request.
+    pub const INVALID_REQUEST: i64 = -32600;
+    /// The method does not exist.
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    /// Invalid method parameters.
+    pub const INVALID_PARAMS: i64 = -32602;
+    /// Internal error.
+    pub const INTERNAL_ERROR: i64 = -32603;
+    /// The call timed out before a response was received.
+    pub const TIMEOUT: i64 = -32000;
+    /// The connection was closed before a response was received.
+    pub const CONNECTION_CLOSED: i64 = -32001;
+
+    /// Creates a new `RpcError` with a code and a message.
+    pub fn new<M: Into<String>>(code: i64, message: M) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    /// Attaches additional information to the error.
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Creates an [`INVALID_PARAMS`](#associatedconstant.INVALID_PARAMS) error.
+    pub fn invalid_params<M: Into<String>>(message: M) -> Self {
+        RpcError::new(RpcError::INVALID_PARAMS, message)
+    }
+
+    /// Creates an [`INTERNAL_ERROR`](#associatedconstant.INTERNAL_ERROR) error.
+    pub fn internal<M: Into<String>>(message: M) -> Self {
+        RpcError::new(RpcError::INTERNAL_ERROR, message)
+    }
+
+    fn method_not_found(method: &str) -> Self {
+        RpcError::new(RpcError::METHOD_NOT_FOUND, format!("Method not found: {}", method))
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "RPC error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// A decoded RPC message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcFrame {
+    /// A method call expecting a response.
+    Request {
+        /// The request id.
+        id: Value,
+        /// The method name.
+        method: String,
+        /// The method parameters.
+        params: Value,
+    },
+    /// A method call without response.
+    Notification {
+        /// The method name.
+        method: String,
+        /// The method parameters.
+        params: Value,
+    },
+    /// The response to a request.
+    Response {
+        /// The id of the request, `Null` if it couldn't be determined.
+        id: Value,
+        /// The result of the call.
+        result: Result<Value, RpcError>,
+    },
+}
+
+/// Encodes and decodes the [`RpcFrame`](./enum.RpcFrame.html)s to and from websocket messages.
+pub trait RpcEnvelope: Send + Sync + 'static {
+    /// Encodes a frame as a message.
+    fn encode(&self, frame: &RpcFrame) -> crate::Result<Message>;
+
+    /// Decodes a message, returning `None` if the message is not meant for the RPC layer.
+    ///
+    /// An error is sent back to the peer as a response with a `Null` id.
+    fn decode(&self, msg: &Message) -> Option<Result<RpcFrame, RpcError>>;
+}
+
+/// The [JSON-RPC 2.0](https://www.jsonrpc.org/specification) envelope, carried in `Text` messages.
+///
+/// Batch requests are not supported and are answered with an [`INVALID_REQUEST`](./struct.RpcError.html#associatedconstant.INVALID_REQUEST) error.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonRpc2;
+
+impl RpcEnvelope for JsonRpc2 {
+    fn encode(&self, frame: &RpcFrame) -> crate::Result<Message> {
+        let value = match frame {
+            RpcFrame::Request { id, method, params } => {
+                with_params(json!({ "jsonrpc": "2.0", "id": id, "method": method }), params)
+            }
+            RpcFrame::Notification { method, params } => {
+                with_params(json!({ "jsonrpc": "2.0", "method": method }), params)
+            }
+            RpcFrame::Response { id, result: Ok(result) } => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            RpcFrame::Response { id, result: Err(err) } => {
+                let mut error = json!({ "code": err.code, "message": err.message });
+                if let Some(ref data) = err.data {
+                    error["data"] = data.clone();
+                }
+                json!({ "jsonrpc": "2.0", "id": id, "error": error })
+            }
+        };
+
+        Message::json(&value)
+    }
+
+    fn decode(&self, msg: &Message) -> Option<Result<RpcFrame, RpcError>> {
+        if !msg.is_text() {
+            return None;
+        }
+
+        let value = match serde_json::from_slice::<Value>(msg.as_bytes()) {
+            Ok(value) => value,
+            Err(err) => return Some(Err(RpcError::new(RpcError::PARSE_ERROR, err.to_string()))),
+        };
+
+        let mut obj = match value {
+            Value::Object(obj) if obj.get("jsonrpc").and_then(Value::as_str) == Some("2.0") => obj,
+            _ => return Some(Err(RpcError::new(RpcError::INVALID_REQUEST, "Invalid request"))),
+        };
+
+        Some(decode_object(&mut obj))
+    }
+}
+
+/// Adds the parameters to a request, they are omitted if `Null` as JSON-RPC only allows arrays and objects.
+fn with_params(mut value: Value, params: &Value) -> Value {
+    if !params.is_null() {
+        value["params"] = params.clone();
+    }
+    value
+}
+
+fn decode_object(obj: &mut Map<String, Value>) -> Result<RpcFrame, RpcError> {
+    let id = obj.remove("id");
+
+    if let Some(method) = obj.remove("method") {
+        let method = match method {
+            Value::String(method) => method,
+            _ => return Err(RpcError::new(RpcError::INVALID_REQUEST, "The method must be a string")),
+        };
+        let params = obj.remove("params").unwrap_or(Value::Null);
+
+        return Ok(match id {
+            Some(id) => RpcFrame::Request { id, method, params },
+            None => RpcFrame::Notification { method, params },
+        });
+    }
+
+    let id = id.unwrap_or(Value::Null);
+    if let Some(result) = obj.remove("result") {
+        return Ok(RpcFrame::Response { id, result: Ok(result) });
+    }
+
+    match obj.remove("error") {
+        Some(Value::Object(mut error)) => {
+            let code = error
+                .get("code")
+                .and_then(Value::as_i64)
+                .unwrap_or(RpcError::INTERNAL_ERROR);
+            let message = match error.remove("message") {
+                Some(Value::String(message)) => message,
+                _ => String::new(),
+            };
+            Ok(RpcFrame::Response {
+                id,
+                result: Err(RpcError {
+                    code,
+                    message,
+                    data: error.remove("data"),
+                }),
+            })
+        }
+        _ => Err(RpcError::new(RpcError::INVALID_REQUEST, "Invalid request")),
+    }
+}
+
+type MethodFuture = Pin<Box<dyn Future<Output = Result<Value, RpcError>> + Send>>;
+type MethodFn = dyn Fn(Value) -> MethodFuture + Send + Sync;
+
+/// The methods the client can call, registered by name.
+#[derive(Clone, Default)]
+pub struct RpcMethods {
+    methods: HashMap<String, Arc<MethodFn>>,
+}
+
+impl RpcMethods {
+    /// Creates an empty set of methods.
+    pub fn new() -> Self {
+        RpcMethods::default()
+    }
+
+    /// Registers an async method.
+    ///
+    /// The parameters are deserialized into `P`, an [`INVALID_PARAMS`](./struct.RpcError.html#associatedconstant.INVALID_PARAMS)
+    /// error is returned to the caller if that fails. The methods registered with the same name are replaced.
+    pub fn method<P, R, F, Fut>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        P: DeserializeOwned + Send + 'static,
+        R: Serialize + Send + 'static,
+        F: Fn(P) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, RpcError>> + Send + 'static,
+    {
+        let f = Arc::new(f);
+        let method = move |params: Value| -> MethodFuture {
+            let f = f.clone();
+            Box::pin(async move {
+                let params =
+                    serde_json::from_value::<P>(params).map_err(|err| RpcError::invalid_params(err.to_string()))?;
+                let result = f(params).await?;
+                serde_json::to_value(result).map_err(|err| RpcError::internal(err.to_string()))
+            })
+        };
+
+        self.methods.insert(name.to_owned(), Arc::new(method));
+        self
+    }
+}
+
+impl fmt::Debug for RpcMethods {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.methods.keys()).finish()
+    }
+}
+
+type PendingCalls = HashMap<u64, oneshot::Sender<Result<Value, RpcError>>>;
+type Pending = Arc<Mutex<PendingCalls>>;
+
+/// A cloneable handle to issue calls and notifications to the client of an [`Rpc`](./struct.Rpc.html) connection.
+#[derive(Debug, Clone)]
+pub struct RpcHandle {
+    tx: mpsc::Sender<RpcFrame>,
+    pending: Pending,
+    next_id: Arc<AtomicU64>,
+    timeout: Duration,
+}
+
+impl RpcHandle {
+    /// Calls a method on the client and waits for its result.
+    ///
+    /// It fails with a [`TIMEOUT`](./struct.RpcError.html#associatedconstant.TIMEOUT) error if no response is received
+    /// in time, or a [`CONNECTION_CLOSED`](./struct.RpcError.html#associatedconstant.CONNECTION_CLOSED) error if the
+    /// connection is closed first.
+    pub async fn call<P: Serialize, R: DeserializeOwned>(&self, method: &str, params: P) -> Result<R, RpcError> {
+        let params = serde_json::to_value(params).map_err(|err| RpcError::invalid_params(err.to_string()))?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+
+        lock(&self.pending).insert(id, tx);
+        let frame = RpcFrame::Request {
+            id: Value::from(id),
+            method: method.to_owned(),
+            params,
+        };
+        if self.tx.send(frame).await.is_err() {
+            lock(&self.pending).remove(&id);
+            return Err(connection_closed());
+        }
+
+        let result = match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(result)) => result?,
+            Ok(Err(_)) => return Err(connection_closed()),
+            Err(_) => {
+                lock(&self.pending).remove(&id);
+                return Err(RpcError::new(RpcError::TIMEOUT, "Request timed out"));
+            }
+        };
+
+        serde_json::from_value(result).map_err(|err| RpcError::internal(err.to_string()))
+    }
+
+    /// Sends a notification to the client, without waiting for any response.
+    ///
+    /// It waits while too many frames are already queued for the connection.
+    pub async fn notify<P: Serialize>(&self, method: &str, params: P) -> Result<(), RpcError> {
+        let params = serde_json::to_value(params).map_err(|err| RpcError::invalid_params(err.to_string()))?;
+        self.tx
+            .send(RpcFrame::Notification {
+                method: method.to_owned(),
+                params,
+            })
+            .await
+            .map_err(|_| connection_closed())
+    }
+}
+
+fn connection_closed() -> RpcError {
+    RpcError::new(RpcError::CONNECTION_CLOSED, "Connection closed")
+}
+
+/// The RPC layer over a websocket connection.
+///
+/// See the [module documentation](./index.html) for an example.
+pub struct Rpc<V = JsonRpc2> {
+    ws: WebSocket,
+    methods: Arc<RpcMethods>,
+    envelope: Arc<V>,
+    handle: RpcHandle,
+    rx: mpsc::Receiver<RpcFrame>,
+    max_concurrent_requests: usize,
+}
+
+impl Rpc<JsonRpc2> {
+    /// Creates the RPC layer over a websocket connection with the [`JsonRpc2`](./struct.JsonRpc2.html) envelope.
+    pub fn new(ws: WebSocket, methods: RpcMethods) -> Self {
+        Rpc::with_envelope(ws, methods, JsonRpc2)
+    }
+}
+
+impl<V: RpcEnvelope> Rpc<V> {
+    /// Creates the RPC layer over a websocket connection with a custom envelope.
+    ///
+    /// The calls time out after `30` seconds by default, and at most `64` incoming requests are served at once.
+    pub fn with_envelope(ws: WebSocket, methods: RpcMethods, envelope: V) -> Self {
+        let (tx, rx) = mpsc::channel(OUTGOING_CAPACITY);
+
+        Rpc {
+            ws,
+            methods: Arc::new(methods),
+            envelope: Arc::new(envelope),
+            handle: RpcHandle {
+                tx,
+                pending: Arc::new(Mutex::new(HashMap::new())),
+                next_id: Arc::new(AtomicU64::new(1)),
+                timeout: Duration::from_secs(30),
+            },
+            rx,
+            max_concurrent_requests: 64,
+        }
+    }
+
+    /// Sets how long the calls wait for a response.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.handle.timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum number of incoming requests and notifications served at once.
+    ///
+    /// The connection is not read while the limit is reached, so a client sending requests faster than they are served
+    /// is slowed down.
+    pub fn max_concurrent_requests(mut self, max: usize) -> Self {
+        self.max_concurrent_requests = max.max(1);
+        self
+    }
+
+    /// Returns a handle to issue calls and notifications to the client.
+    pub fn handle(&self) -> RpcHandle {
+        self.handle.clone()
+    }
+
+    /// Serves the connection until it is closed or fails.
+    ///
+    /// The incoming requests are served concurrently, each one in its own task, up to the
+    /// [`max_concurrent_requests`](#method.max_concurrent_requests) limit. When the connection stops being served, the
+    /// requests still running are aborted and the calls still waiting for a response fail.
+    pub async fn run(self) -> crate::Result<()> {
+        let Rpc {
+            mut ws,
+            methods,
+            envelope,
+            handle,
+            rx,
+            max_concurrent_requests,
+        } = self;
+
+        let mut outgoing = Outgoing {
+            rx,
+            pending: &handle.pending,
+        };
+        let mut tasks = JoinSet::new();
+        // The ids of the requests being served, by task.
+        let mut requests = HashMap::<task::Id, Value>::new();
+
+        loop {
+            let next = poll_fn(|cx| {
+                if let Poll::Ready(Some(frame)) = outgoing.rx.poll_recv(cx) {
+                    return Poll::Ready(Next::Outgoing(frame));
+                }
+                if let Poll::Ready(Some(result)) = tasks.poll_join_next_with_id(cx) {
+                    return Poll::Ready(Next::Served(result));
+                }
+                if tasks.len() >= max_concurrent_requests {
+                    return Poll::Pending;
+                }
+                ws.poll_next_unpin(cx).map(Next::Incoming)
+            })
+            .await;
+
+            let msg = match next {
+                Next::Outgoing(frame) => {
+                    ws.send(envelope.encode(&frame)?).await?;
+                    continue;
+                }
+                Next::Served(Ok((task, frame))) => {
+                    requests.remove(&task);
+                    // A notification has no response.
+                    if let Some(frame) = frame {
+                        ws.send(envelope.encode(&frame)?).await?;
+                    }
+                    continue;
+                }
+                Next::Served(Err(err)) => {
+                    // The method panicked, its caller still gets a response.
+                    if let Some(id) = requests.remove(&err.id()) {
+                        let frame = RpcFrame::Response {
+                            id,
+                            result: Err(RpcError::internal("The method panicked")),
+                        };
+                        ws.send(envelope.encode(&frame)?).await?;
+                    }
+                    continue;
+                }
+                Next::Incoming(Some(msg)) => msg?,
+                Next::Incoming(None) => return Ok(()),
+            };
+
+            match envelope.decode(&msg) {
+                None => {}
+                Some(Ok(RpcFrame::Request { id, method, params })) => {
+                    let call = methods.methods.get(&method).cloned();
+                    let response_id = id.clone();
+                    let task = tasks.spawn(async move {
+                        let result = match call {
+                            Some(call) => call(params).await,
+                            None => Err(RpcError::method_not_found(&method)),
+                        };
+                        Some(RpcFrame::Response {
+                            id: response_id,
+                            result,
+                        })
+                    });
+                    requests.insert(task.id(), id);
+                }
+                Some(Ok(RpcFrame::Notification { method, params })) => {
+                    if let Some(call) = methods.methods.get(&method).cloned() {
+                        tasks.spawn(async move {
+                            let _ = call(params).await;
+                            None
+                        });
+                    }
+                }
+                Some(Ok(RpcFrame::Response { id, result })) => {
+                    let sender = id.as_u64().and_then(|id| lock(&handle.pending).remove(&id));
+                    if let Some(sender) = sender {
+                        let _ = sender.send(result);
+                    }
+                }
+                Some(Err(err)) => {
+                    let frame = RpcFrame::Response {
+                        id: Value::Null,
+                        result: Err(err),
+                    };
+                    ws.send(envelope.encode(&frame)?).await?;
+                }
+            }
+        }
+    }
+}
+
+/// The queue of the outgoing calls and notifications, which fails the calls still waiting for a response once the
+/// connection stops being served, whichever way it stops.
+struct Outgoing<'a> {
+    rx: mpsc::Receiver<RpcFrame>,
+    pending: &'a Pending,
+}
+
+impl Drop for Outgoing<'_> {
+    fn drop(&mut self) {
+        // The queue is closed first, so the calls made meanwhile fail to be queued instead of waiting for a response.
+        self.rx.close();
+        lock(self.pending).clear();
+    }
+}
+
+fn lock(pending: &Pending) -> MutexGuard<'_, PendingCalls> {
+    pending.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+enum Next {
+    Outgoing(RpcFrame),
+    Served(Result<(task::Id, Option<RpcFrame>), JoinError>),
+    Incoming(Option<crate::Result<Message>>),
+}
+
+impl<V> fmt::Debug for Rpc<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rpc")
+            .field("ws", &self.ws)
+            .field("methods", &self.methods)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upgrade_ws;
+    use hyper::{Body, Server};
+    use routerify::{Router, RouterService};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tokio_tungstenite::tungstenite::Message as ClientMessage;
+
+    async fn serve(methods: fn() -> RpcMethods) -> SocketAddr {
+        let router: Router<Body, Infallible> = Router::builder()
+            .any_method(
+                "/ws",
+                upgrade_ws(move |ws| async move {
+                    let _ = Rpc::new(ws, methods()).run().await;
+                }),
+            )
+            .build()
+            .unwrap();
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(RouterService::new(router).unwrap());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn answers_the_requests_whose_method_panicked() {
+        let addr = serve(|| {
+            let mut methods = RpcMethods::new();
+            methods.method("panic", |_: Value| async move {
+                if true {
+                    panic!("the method panicked");
+                }
+                Ok::<_, RpcError>(())
+            });
+            methods.method("add", |(a, b): (i64, i64)| async move { Ok::<_, RpcError>(a + b) });
+            methods
+        })
+        .await;
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+
+        let requests = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "panic" }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "add", "params": [1, 2] }),
+        ];
+        for request in &requests {
+            client.send(ClientMessage::text(request.to_string())).await.unwrap();
+        }
+
+        let mut responses = HashMap::new();
+        while responses.len() < 2 {
+            let msg = client.next().await.unwrap().unwrap();
+            let response = serde_json::from_slice::<Value>(&msg.into_data()).unwrap();
+            responses.insert(response["id"].as_i64().unwrap(), response);
+        }
+
+        assert_eq!(responses[&1]["error"]["code"], RpcError::INTERNAL_ERROR);
+        assert_eq!(responses[&2]["result"], 3);
+    }
+}
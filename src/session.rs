@@ -0,0 +1,141 @@
+use crate::message::close_reason;
+use crate::{CloseCode, Message, MiddlewareContext};
+use hyper::{http::Extensions, Body, Request};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+/// The close code sent when a session expires, in the range reserved for the applications.
+const EXPIRED_CODE: u16 = 4001;
+
+/// The close reason sent when a session expires.
+const EXPIRED_REASON: &str = "Session expired";
+
+type ExpiresAtFn = dyn Fn(&Request<Body>, &Extensions) -> Option<Instant> + Send + Sync;
+pub(crate) type RefreshFn = dyn Fn(&mut MiddlewareContext, &Message) -> Option<Instant> + Send + Sync;
+
+/// The expiry of the session a websocket connection was authenticated with, e.g. the expiry of a JWT.
+///
+/// The expiry is determined when the connection is upgraded, and the requests whose session has already expired are
+/// rejected with `401 Unauthorized`. Once the session expires without being refreshed, the connection is closed with
+/// the `4001` code and the `Session expired` reason, and a
+/// [`WebsocketError::SessionExpired`](./enum.WebsocketError.html#variant.SessionExpired) error is returned by the
+/// [`WebSocket`](./struct.WebSocket.html) stream or sink. The expiry is a timer polled whenever the connection is read
+/// or written, streamed messages included, so a handler only sending messages has its connection closed as well, at
+/// the latest with the first message it sends after the expiry.
+///
+/// The session is extended by the re-authentication messages recognized by the [`refresh`](#method.refresh) hook, or
+/// with [`WebSocket::set_expires_at`](./struct.WebSocket.html#method.set_expires_at).
+///
+/// # Examples
+///
+/// ```
+/// use routerify_websocket::{SessionExpiry, UpgradeConfig};
+/// use std::time::{Duration, Instant};
+///
+/// struct Claims {
+///     expires_at: Instant,
+/// }
+///
+/// fn verify(token: &str) -> Option<Claims> {
+///     // Verify the token and decode its claims.
+///     # let _ = token;
+///     Some(Claims {
+///         expires_at: Instant::now() + Duration::from_secs(15 * 60),
+///     })
+/// }
+///
+/// let session = SessionExpiry::new(|_req, extensions| {
+///     // The claims are inserted by a guard authenticating the request.
+///     extensions.get::<Claims>().map(|claims| claims.expires_at)
+/// })
+/// .refresh(|_ctx, msg| {
+///     // The clients send `auth <token>` messages with a fresh token before the session expires.
+///     let token = msg.as_text().ok()?.strip_prefix("auth ")?;
+///     verify(token).map(|claims| claims.expires_at)
+/// });
+///
+/// let config = UpgradeConfig::new()
+///     .guard(|req, extensions| {
+///         let token = req.headers().get("x-token").and_then(|val| val.to_str().ok());
+///         let claims = token.and_then(verify).ok_or(hyper::StatusCode::UNAUTHORIZED)?;
+///         extensions.insert(claims);
+///         Ok(())
+///     })
+///     .session_expiry(session);
+/// ```
+#[derive(Clone)]
+pub struct SessionExpiry {
+    pub(crate) expires_at: Arc<ExpiresAtFn>,
+    pub(crate) refresh: Option<Arc<RefreshFn>>,
+    close_code: CloseCode,
+    reason: Cow<'static, str>,
+}
+
+impl SessionExpiry {
+    /// Creates a new `SessionExpiry` with the hook determining when the session of an upgrade request expires.
+    ///
+    /// The hook receives the upgrade request and the extensions inserted by the
+    /// [guards](./struct.UpgradeConfig.html#method.guard). The session never expires if it returns `None`.
+    pub fn new<F>(expires_at: F) -> Self
+    where
+        F: Fn(&Request<Body>, &Extensions) -> Option<Instant> + Send + Sync + 'static,
+    {
+        SessionExpiry {
+            expires_at: Arc::new(expires_at),
+            refresh: None,
+            close_code: CloseCode::from(EXPIRED_CODE),
+            reason: Cow::Borrowed(EXPIRED_REASON),
+        }
+    }
+
+    /// Sets the hook recognizing the in-band re-authentication messages.
+    ///
+    /// The hook is called with the incoming `Text` and `Binary` messages, after the
+    /// [middlewares](./trait.WebSocketMiddleware.html). If it returns the new expiry of the session, the message is
+    /// consumed and not returned by the [`WebSocket`](./struct.WebSocket.html) stream.
+    pub fn refresh<F>(mut self, refresh: F) -> Self
+    where
+        F: Fn(&mut MiddlewareContext, &Message) -> Option<Instant> + Send + Sync + 'static,
+    {
+        self.refresh = Some(Arc::new(refresh));
+        self
+    }
+
+    /// Sets the code the connection is closed with when the session expires, `4001` by default.
+    pub fn close_code(mut self, code: CloseCode) -> Self {
+        self.close_code = code;
+        self
+    }
+
+    /// Sets the reason the connection is closed with when the session expires, `Session expired` by default.
+    pub fn reason<R: Into<Cow<'static, str>>>(mut self, reason: R) -> Self {
+        self.reason = reason.into();
+        self
+    }
+}
+
+impl fmt::Debug for SessionExpiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SessionExpiry")
+            .field("close_code", &self.close_code)
+            .field("reason", &self.reason)
+            .finish()
+    }
+}
+
+/// The close frame sent when the session of a connection expires.
+pub(crate) fn expired_frame(session: Option<&SessionExpiry>) -> CloseFrame {
+    match session {
+        Some(session) => CloseFrame {
+            code: session.close_code,
+            reason: close_reason(session.reason.clone()),
+        },
+        None => CloseFrame {
+            code: CloseCode::from(EXPIRED_CODE),
+            reason: close_reason(Cow::Borrowed(EXPIRED_REASON)),
+        },
+    }
+}
@@ -0,0 +1,929 @@
+//! A [Socket.IO](https://socket.io/docs/v4/socket-io-protocol/) compatible endpoint, for the `socket.io-client`
+//! clients.
+//!
+//! It speaks the Engine.IO v4 packets over the websocket transport: the `open` handshake packet, the `ping`/`pong`
+//! keep-alive driven by the server and the `message` packets, which carry the Socket.IO v5 packets. The clients
+//! connect to the [`Namespace`](./struct.Namespace.html)s of a [`SocketIo`](./struct.SocketIo.html) server, then
+//! exchange events and acknowledgements through a [`Socket`](./struct.Socket.html).
+//!
+//! Only the websocket transport is supported, so the clients must be configured with `transports: ["websocket"]`,
+//! and the route is usually mounted at `/socket.io/`. The binary events and acknowledgements are not supported and
+//! are ignored.
+//!
+//! # Optional
+//!
+//! This requires the optional `socketio` feature to be enabled.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use hyper::Body;
+//! # use routerify::Router;
+//! use routerify_websocket::socketio::{upgrade_socketio, Namespace, SocketIo};
+//! # use std::convert::Infallible;
+//!
+//! fn router() -> Router<Body, Infallible> {
+//!     let mut chat = Namespace::new();
+//!     chat.on("message", |socket, (text,): (String,), ack| async move {
+//!         // Echo the message back, and acknowledge it if the client asked to.
+//!         socket.emit("message", (&text,)).unwrap();
+//!         if let Some(ack) = ack {
+//!             ack.send(("received",)).unwrap();
+//!         }
+//!     });
+//!
+//!     let io = SocketIo::new().namespace("/", chat);
+//!
+//!     Router::builder()
+//!         .any_method("/socket.io/", upgrade_socketio(io))
+//!         .build()
+//!         .unwrap()
+//! }
+//! ```
+
+use crate::upgrade::upgrade_with;
+use crate::{CloseCode, Message, UpgradeConfig, WebSocket, WebSocketConfig};
+use futures::future::{poll_fn, Ready};
+use futures::{SinkExt, StreamExt};
+use hyper::{body::HttpBody, Request, Response};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::{Instant, Interval, Sleep};
+
+static NEXT_SID: AtomicU64 = AtomicU64::new(1);
+
+/// The largest payload advertised in the handshake, the Engine.IO default. It only matters to the polling transport.
+const MAX_PAYLOAD: u64 = 1_000_000;
+
+/// The number of events of a socket queued while its handlers run, before the connection stops being read.
+const EVENT_QUEUE_CAPACITY: usize = 64;
+
+/// The number of packets queued for a client by its sockets, before the client is disconnected as too slow.
+const OUTGOING_CAPACITY: usize = 1024;
+
+/// An error while emitting an event to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmitError {
+    /// The arguments could not be serialized.
+    Serialize(String),
+    /// The client did not acknowledge the event in time.
+    Timeout,
+    /// The client disconnected from the namespace.
+    Disconnected,
+}
+
+impl Display for EmitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EmitError::Serialize(err) => write!(f, "Couldn't serialize the event arguments: {}", err),
+            EmitError::Timeout => f.write_str("The event was not acknowledged in time"),
+            EmitError::Disconnected => f.write_str("The socket is disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for EmitError {}
+
+/// An Engine.IO packet received from the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnginePacket<'a> {
+    Close,
+    Ping(&'a str),
+    Pong,
+    Message(&'a str),
+}
+
+impl<'a> EnginePacket<'a> {
+    /// Parses a packet, returning `None` if it is invalid or not expected from a client.
+    fn parse(s: &'a str) -> Option<EnginePacket<'a>> {
+        let payload = s.get(1..)?;
+        match s.as_bytes().first()? {
+            b'1' => Some(EnginePacket::Close),
+            b'2' => Some(EnginePacket::Ping(payload)),
+            b'3' => Some(EnginePacket::Pong),
+            b'4' => Some(EnginePacket::Message(payload)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketKind {
+    Connect,
+    Disconnect,
+    Event,
+    Ack,
+    ConnectError,
+}
+
+/// A Socket.IO packet, carried in an Engine.IO `message` packet.
+#[derive(Debug, Clone, PartialEq)]
+struct Packet {
+    kind: PacketKind,
+    namespace: String,
+    id: Option<u64>,
+    data: Option<Value>,
+}
+
+impl Packet {
+    fn new(kind: PacketKind, namespace: &str, id: Option<u64>, data: Option<Value>) -> Self {
+        Packet {
+            kind,
+            namespace: namespace.to_owned(),
+            id,
+            data,
+        }
+    }
+
+    /// Parses a packet, returning `None` if it is invalid or a binary packet.
+    fn parse(s: &str) -> Option<Packet> {
+        let kind = match s.as_bytes().first()? {
+            b'0' => PacketKind::Connect,
+            b'1' => PacketKind::Disconnect,
+            b'2' => PacketKind::Event,
+            b'3' => PacketKind::Ack,
+            b'4' => PacketKind::ConnectError,
+            _ => return None,
+        };
+        let mut rest = &s[1..];
+
+        let mut namespace = "/";
+        if rest.starts_with('/') {
+            let end = rest.find(',').unwrap_or(rest.len());
+            namespace = &rest[..end];
+            rest = rest.get(end + 1..).unwrap_or("");
+        }
+        // The query string of the namespace was replaced by the auth payload in Socket.IO v5, ignore it.
+        let namespace = namespace.split('?').next().unwrap_or("/");
+
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let id = match digits {
+            0 => None,
+            _ => Some(rest[..digits].parse().ok()?),
+        };
+        rest = &rest[digits..];
+
+        let data = match rest {
+            "" => None,
+            rest => Some(serde_json::from_str(rest).ok()?),
+        };
+
+        Some(Packet::new(kind, namespace, id, data))
+    }
+
+    /// Encodes the packet in an Engine.IO `message` packet.
+    fn encode(&self) -> String {
+        let kind = match self.kind {
+            PacketKind::Connect => '0',
+            PacketKind::Disconnect => '1',
+            PacketKind::Event => '2',
+            PacketKind::Ack => '3',
+            PacketKind::ConnectError => '4',
+        };
+
+        let mut out = format!("4{}", kind);
+        if self.namespace != "/" {
+            out.push_str(&self.namespace);
+            out.push(',');
+        }
+        if let Some(id) = self.id {
+            out.push_str(&id.to_string());
+        }
+        if let Some(ref data) = self.data {
+            out.push_str(&data.to_string());
+        }
+
+        out
+    }
+}
+
+/// Converts the arguments to the list of arguments of an event, a non array value being a single argument.
+fn to_args<A: Serialize>(args: A) -> Result<Vec<Value>, EmitError> {
+    match serde_json::to_value(args).map_err(|err| EmitError::Serialize(err.to_string()))? {
+        Value::Array(args) => Ok(args),
+        Value::Null => Ok(Vec::new()),
+        arg => Ok(vec![arg]),
+    }
+}
+
+fn new_sid() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    format!("{:016x}{:08x}", nanos, NEXT_SID.fetch_add(1, Ordering::Relaxed))
+}
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Vec<Value>>>>>;
+
+/// A packet sent by a socket, dropped if the socket is not connected anymore when it is sent.
+struct Outgoing {
+    sid: String,
+    packet: Packet,
+}
+
+/// A client connected to a namespace, a cloneable handle to emit events to it.
+#[derive(Debug, Clone)]
+pub struct Socket {
+    sid: String,
+    namespace: String,
+    tx: mpsc::Sender<Outgoing>,
+    slow: Arc<Notify>,
+    connected: Arc<AtomicBool>,
+    pending: Pending,
+    next_id: Arc<AtomicU64>,
+    ack_timeout: Duration,
+}
+
+impl Socket {
+    /// The session id of the socket, unique per namespace connection.
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    /// The namespace the socket is connected to.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Returns true until the socket is disconnected from the namespace.
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// Emits an event to the client.
+    ///
+    /// The arguments are usually a tuple or a `Vec`, serialized as the argument list. Any other value is sent as a
+    /// single argument, and `()` as no argument. It fails with
+    /// [`EmitError::Disconnected`](./enum.EmitError.html#variant.Disconnected) once the socket is disconnected, and
+    /// the client is disconnected when it doesn't read the packets queued for it fast enough.
+    pub fn emit<A: Serialize>(&self, event: &str, args: A) -> Result<(), EmitError> {
+        self.send_event(event, to_args(args)?, None)
+    }
+
+    /// Emits an event to the client and waits for its acknowledgement arguments.
+    ///
+    /// It fails with [`EmitError::Timeout`](./enum.EmitError.html#variant.Timeout) if the client does not
+    /// acknowledge the event in time, `10` seconds by default.
+    pub async fn emit_with_ack<A: Serialize, R: DeserializeOwned>(&self, event: &str, args: A) -> Result<R, EmitError> {
+        let args = to_args(args)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+
+        self.pending.lock().unwrap().insert(id, tx);
+        if let Err(err) = self.send_event(event, args, Some(id)) {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(err);
+        }
+
+        let result = match tokio::time::timeout(self.ack_timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => return Err(EmitError::Disconnected),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                return Err(EmitError::Timeout);
+            }
+        };
+
+        serde_json::from_value(Value::Array(result)).map_err(|err| EmitError::Serialize(err.to_string()))
+    }
+
+    /// Disconnects the client from the namespace.
+    ///
+    /// The events emitted before are still sent to the client, the ones emitted afterwards are refused.
+    pub fn disconnect(&self) -> Result<(), EmitError> {
+        self.send(Packet::new(PacketKind::Disconnect, &self.namespace, None, None))?;
+        self.connected.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    fn send_event(&self, event: &str, mut args: Vec<Value>, id: Option<u64>) -> Result<(), EmitError> {
+        args.insert(0, Value::from(event));
+        self.send(Packet::new(
+            PacketKind::Event,
+            &self.namespace,
+            id,
+            Some(Value::Array(args)),
+        ))
+    }
+
+    fn send(&self, packet: Packet) -> Result<(), EmitError> {
+        if !self.connected() {
+            return Err(EmitError::Disconnected);
+        }
+        match self.tx.try_send(Outgoing {
+            sid: self.sid.clone(),
+            packet,
+        }) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.connected.store(false, Ordering::Release);
+                self.slow.notify_one();
+                Err(EmitError::Disconnected)
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(EmitError::Disconnected),
+        }
+    }
+}
+
+/// The acknowledgement requested by the client for an event.
+#[derive(Debug)]
+pub struct Ack {
+    id: u64,
+    socket: Socket,
+}
+
+impl Ack {
+    /// Sends the acknowledgement arguments, with the same conventions as [`Socket::emit`](./struct.Socket.html#method.emit).
+    pub fn send<A: Serialize>(self, args: A) -> Result<(), EmitError> {
+        let packet = Packet::new(
+            PacketKind::Ack,
+            &self.socket.namespace,
+            Some(self.id),
+            Some(Value::Array(to_args(args)?)),
+        );
+        self.socket.send(packet)
+    }
+}
+
+type HookFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type ConnectFn = dyn Fn(Socket, Value) -> HookFuture<Result<(), String>> + Send + Sync;
+type DisconnectFn = dyn Fn(Socket, String) -> HookFuture<()> + Send + Sync;
+type EventFn = dyn Fn(Socket, Vec<Value>, Option<Ack>) -> HookFuture<()> + Send + Sync;
+
+/// The event handlers of a namespace.
+#[derive(Clone, Default)]
+pub struct Namespace {
+    connect: Option<Arc<ConnectFn>>,
+    disconnect: Option<Arc<DisconnectFn>>,
+    events: HashMap<String, Arc<EventFn>>,
+}
+
+impl Namespace {
+    /// Creates a namespace without handlers, accepting all the clients.
+    pub fn new() -> Self {
+        Namespace::default()
+    }
+
+    /// Sets the hook called when a client connects to the namespace, with its auth payload or `null`.
+    ///
+    /// The connection is refused with a `CONNECT_ERROR` packet carrying the message if the hook fails. The events of
+    /// the client are handled only after the hook returns.
+    pub fn on_connect<F, Fut>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(Socket, Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.connect = Some(Arc::new(move |socket, auth| -> HookFuture<_> {
+            Box::pin(f(socket, auth))
+        }));
+        self
+    }
+
+    /// Sets the hook called when a client is disconnected from the namespace, with the reason.
+    pub fn on_disconnect<F, Fut>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(Socket, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.disconnect = Some(Arc::new(move |socket, reason| -> HookFuture<_> {
+            Box::pin(f(socket, reason))
+        }));
+        self
+    }
+
+    /// Registers an async event handler.
+    ///
+    /// The argument list is deserialized into `A`, usually a tuple or a `Vec<Value>`, and the event is ignored if that
+    /// fails. The handler receives an [`Ack`](./struct.Ack.html) if the client asked for an acknowledgement. The
+    /// handlers registered with the same name are replaced.
+    ///
+    /// The events of a socket are handled one at a time and in order, by a task of the socket, so a handler waiting
+    /// for an acknowledgement holds back the next events of the client. The sockets are handled concurrently.
+    pub fn on<A, F, Fut>(&mut self, event: &str, f: F) -> &mut Self
+    where
+        A: DeserializeOwned + Send + 'static,
+        F: Fn(Socket, A, Option<Ack>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let f = Arc::new(f);
+        let event_name = event.to_owned();
+        let handler = move |socket: Socket, args: Vec<Value>, ack: Option<Ack>| -> HookFuture<()> {
+            let f = f.clone();
+            let event_name = event_name.clone();
+            Box::pin(async move {
+                match serde_json::from_value::<A>(Value::Array(args)) {
+                    Ok(args) => f(socket, args, ack).await,
+                    Err(err) => log::debug!("Ignoring the socket.io event {}: {}", event_name, err),
+                }
+            })
+        };
+
+        self.events.insert(event.to_owned(), Arc::new(handler));
+        self
+    }
+}
+
+impl fmt::Debug for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.events.keys()).finish()
+    }
+}
+
+/// A Socket.IO server, the namespaces the clients can connect to.
+#[derive(Debug, Clone)]
+pub struct SocketIo {
+    namespaces: HashMap<String, Arc<Namespace>>,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    ack_timeout: Duration,
+}
+
+impl SocketIo {
+    /// Creates a server without namespaces.
+    ///
+    /// The server pings the clients every `25` seconds and waits `20` seconds for their pong, the Socket.IO defaults.
+    pub fn new() -> Self {
+        SocketIo {
+            namespaces: HashMap::new(),
+            ping_interval: Duration::from_secs(25),
+            ping_timeout: Duration::from_secs(20),
+            ack_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Adds a namespace, replacing the one with the same name. The main namespace is `/`.
+    pub fn namespace(mut self, name: &str, namespace: Namespace) -> Self {
+        self.namespaces.insert(name.to_owned(), Arc::new(namespace));
+        self
+    }
+
+    /// Sets how often the clients are pinged.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Sets how long the clients have to answer a ping before they are disconnected.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Sets how long [`Socket::emit_with_ack`](./struct.Socket.html#method.emit_with_ack) waits for the acknowledgement.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+}
+
+impl Default for SocketIo {
+    fn default() -> Self {
+        SocketIo::new()
+    }
+}
+
+/// A socket connected to a namespace, with the queue of its event handlers.
+struct Connected {
+    socket: Socket,
+    events: mpsc::Sender<HookFuture<()>>,
+}
+
+impl Connected {
+    /// Connects a socket, spawning the task running its event handlers in order.
+    fn new(socket: Socket) -> Self {
+        let (events, mut queue) = mpsc::channel::<HookFuture<()>>(EVENT_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(handler) = queue.recv().await {
+                handler.await;
+            }
+        });
+
+        Connected { socket, events }
+    }
+}
+
+enum Next {
+    Outgoing(Outgoing),
+    SlowConsumer,
+    Ping,
+    PongTimeout,
+    Incoming(Option<crate::Result<Message>>),
+}
+
+/// Serves an Engine.IO v4 session on an upgraded websocket connection with the server, until it is closed.
+pub async fn serve(ws: WebSocket, io: Arc<SocketIo>) -> crate::Result<()> {
+    let mut ws = ws;
+    let (tx, mut rx) = mpsc::channel::<Outgoing>(OUTGOING_CAPACITY);
+    let slow = Arc::new(Notify::new());
+    let mut slow_consumer = std::pin::pin!(slow.notified());
+    let mut sockets = HashMap::<String, Connected>::new();
+    let mut ping: Interval = tokio::time::interval_at(Instant::now() + io.ping_interval, io.ping_interval);
+    let mut pong_deadline: Option<Pin<Box<Sleep>>> = None;
+
+    let open = json!({
+        "sid": new_sid(),
+        "upgrades": [],
+        "pingInterval": io.ping_interval.as_millis() as u64,
+        "pingTimeout": io.ping_timeout.as_millis() as u64,
+        "maxPayload": MAX_PAYLOAD,
+    });
+    ws.send(Message::text(format!("0{}", open))).await?;
+
+    let (result, reason) = loop {
+        let next = poll_fn(|cx| {
+            if let Some(ref mut deadline) = pong_deadline {
+                if deadline.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Next::PongTimeout);
+                }
+            }
+            if ping.poll_tick(cx).is_ready() {
+                return Poll::Ready(Next::Ping);
+            }
+            if slow_consumer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Next::SlowConsumer);
+            }
+            if let Poll::Ready(Some(outgoing)) = rx.poll_recv(cx) {
+                return Poll::Ready(Next::Outgoing(outgoing));
+            }
+            ws.poll_next_unpin(cx).map(Next::Incoming)
+        })
+        .await;
+
+        let msg = match next {
+            Next::Outgoing(Outgoing { sid, packet }) => {
+                // The socket was disconnected meanwhile, possibly replaced by a new connection to the namespace.
+                if !sockets
+                    .get(&packet.namespace)
+                    .is_some_and(|connected| connected.socket.sid == sid)
+                {
+                    continue;
+                }
+                if let Err(err) = ws.send(Message::text(packet.encode())).await {
+                    break (Err(err), "transport error");
+                }
+                if packet.kind == PacketKind::Disconnect {
+                    if let Some(connected) = sockets.remove(&packet.namespace) {
+                        disconnected(&io, connected, "server namespace disconnect").await;
+                    }
+                }
+                continue;
+            }
+            Next::Ping => {
+                if let Err(err) = ws.send(Message::text("2")).await {
+                    break (Err(err), "transport error");
+                }
+                if pong_deadline.is_none() {
+                    pong_deadline = Some(Box::pin(tokio::time::sleep(io.ping_timeout)));
+                }
+                continue;
+            }
+            Next::SlowConsumer => {
+                let result = ws
+                    .close_with(CloseCode::Again, "Too many packets queued for the client")
+                    .await;
+                break (result, "forced server close");
+            }
+            Next::PongTimeout => break (ws.close().await, "ping timeout"),
+            Next::Incoming(Some(Ok(msg))) => msg,
+            Next::Incoming(Some(Err(err))) => break (Err(err), "transport error"),
+            Next::Incoming(None) => break (Ok(()), "transport close"),
+        };
+
+        let text = match msg.as_text() {
+            Ok(text) if msg.is_text() => text,
+            _ => continue,
+        };
+
+        match EnginePacket::parse(text) {
+            Some(EnginePacket::Close) => break (ws.close().await, "client namespace disconnect"),
+            // The ping is answered with the same payload, like the `probe` ping.
+            Some(EnginePacket::Ping(payload)) => {
+                if let Err(err) = ws.send(Message::text(format!("3{}", payload))).await {
+                    break (Err(err), "transport error");
+                }
+            }
+            Some(EnginePacket::Pong) => pong_deadline = None,
+            Some(EnginePacket::Message(data)) => {
+                let packet = match Packet::parse(data) {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+                if let Err(err) = handle_packet(&mut ws, &io, &tx, &slow, &mut sockets, packet).await {
+                    break (Err(err), "transport error");
+                }
+            }
+            None => {}
+        }
+    };
+
+    for (_, connected) in sockets.drain() {
+        disconnected(&io, connected, reason).await;
+    }
+
+    result
+}
+
+async fn handle_packet(
+    ws: &mut WebSocket,
+    io: &SocketIo,
+    tx: &mpsc::Sender<Outgoing>,
+    slow: &Arc<Notify>,
+    sockets: &mut HashMap<String, Connected>,
+    packet: Packet,
+) -> crate::Result<()> {
+    let Packet {
+        kind,
+        namespace: name,
+        id,
+        data,
+    } = packet;
+
+    match kind {
+        PacketKind::Connect => {
+            let namespace = match io.namespaces.get(&name) {
+                Some(namespace) => namespace.clone(),
+                None => return send_connect_error(ws, &name, "Invalid namespace").await,
+            };
+
+            let socket = Socket {
+                sid: new_sid(),
+                namespace: name.clone(),
+                tx: tx.clone(),
+                slow: slow.clone(),
+                connected: Arc::new(AtomicBool::new(true)),
+                pending: Arc::new(Mutex::new(HashMap::new())),
+                next_id: Arc::new(AtomicU64::new(0)),
+                ack_timeout: io.ack_timeout,
+            };
+            if let Some(ref connect) = namespace.connect {
+                if let Err(message) = connect(socket.clone(), data.unwrap_or(Value::Null)).await {
+                    socket.connected.store(false, Ordering::Release);
+                    return send_connect_error(ws, &name, &message).await;
+                }
+            }
+
+            let packet = Packet::new(PacketKind::Connect, &name, None, Some(json!({ "sid": socket.sid })));
+            ws.send(Message::text(packet.encode())).await?;
+            if let Some(previous) = sockets.insert(name, Connected::new(socket)) {
+                disconnected(io, previous, "client namespace disconnect").await;
+            }
+        }
+        PacketKind::Disconnect => {
+            if let Some(connected) = sockets.remove(&name) {
+                disconnected(io, connected, "client namespace disconnect").await;
+            }
+        }
+        PacketKind::Event => {
+            let (connected, mut args) = match (sockets.get(&name), data) {
+                (Some(connected), Some(Value::Array(args))) if !args.is_empty() => (connected, args),
+                _ => return Ok(()),
+            };
+            let event = match args.remove(0) {
+                Value::String(event) => event,
+                _ => return Ok(()),
+            };
+
+            let handler = io
+                .namespaces
+                .get(&name)
+                .and_then(|namespace| namespace.events.get(&event).cloned());
+            if let Some(handler) = handler {
+                let socket = connected.socket.clone();
+                let ack = id.map(|id| Ack {
+                    id,
+                    socket: socket.clone(),
+                });
+                let _ = connected.events.send(handler(socket, args, ack)).await;
+            }
+        }
+        PacketKind::Ack => {
+            let sender = match (sockets.get(&name), id) {
+                (Some(connected), Some(id)) => connected.socket.pending.lock().unwrap().remove(&id),
+                _ => None,
+            };
+            if let (Some(sender), Some(Value::Array(args))) = (sender, data) {
+                let _ = sender.send(args);
+            }
+        }
+        PacketKind::ConnectError => {}
+    }
+
+    Ok(())
+}
+
+async fn send_connect_error(ws: &mut WebSocket, namespace: &str, message: &str) -> crate::Result<()> {
+    let packet = Packet::new(
+        PacketKind::ConnectError,
+        namespace,
+        None,
+        Some(json!({ "message": message })),
+    );
+    ws.send(Message::text(packet.encode())).await
+}
+
+/// Marks a socket as disconnected, fails its pending acknowledgements and queues the disconnect hook of its namespace
+/// after its events.
+async fn disconnected(io: &SocketIo, connected: Connected, reason: &str) {
+    let Connected { socket, events } = connected;
+    socket.connected.store(false, Ordering::Release);
+    socket.pending.lock().unwrap().clear();
+
+    let hook = io
+        .namespaces
+        .get(&socket.namespace)
+        .and_then(|namespace| namespace.disconnect.clone());
+    if let Some(hook) = hook {
+        let _ = events.send(hook(socket, reason.to_owned())).await;
+    }
+}
+
+/// Upgrades the http requests to websocket and serves them as Engine.IO v4 sessions of the provided Socket.IO server,
+/// with the provided config.
+///
+/// The config can either be an [`UpgradeConfig`](../struct.UpgradeConfig.html) or a plain [`WebSocketConfig`](../struct.WebSocketConfig.html).
+pub fn upgrade_socketio_with_config<B, E, C>(
+    io: SocketIo,
+    config: C,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+    C: Into<UpgradeConfig>,
+{
+    let io = Arc::new(io);
+
+    upgrade_with(
+        move |ws| {
+            let io = io.clone();
+            async move {
+                if let Err(err) = serve(ws, io).await {
+                    log::error!("{}", err);
+                }
+            }
+        },
+        config.into(),
+    )
+}
+
+/// Upgrades the http requests to websocket and serves them as Engine.IO v4 sessions of the provided Socket.IO server.
+pub fn upgrade_socketio<B, E>(
+    io: SocketIo,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    upgrade_socketio_with_config(io, WebSocketConfig::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn parse_engine_packets() {
+        assert_eq!(EnginePacket::parse("1"), Some(EnginePacket::Close));
+        assert_eq!(EnginePacket::parse("2"), Some(EnginePacket::Ping("")));
+        assert_eq!(EnginePacket::parse("2probe"), Some(EnginePacket::Ping("probe")));
+        assert_eq!(EnginePacket::parse("3"), Some(EnginePacket::Pong));
+        assert_eq!(
+            EnginePacket::parse("42[\"a\"]"),
+            Some(EnginePacket::Message("2[\"a\"]"))
+        );
+        assert_eq!(EnginePacket::parse("0{}"), None);
+        assert_eq!(EnginePacket::parse("6"), None);
+        assert_eq!(EnginePacket::parse(""), None);
+        assert_eq!(EnginePacket::parse("é"), None);
+    }
+
+    #[test]
+    fn parse_connect_packets() {
+        assert_eq!(
+            Packet::parse("0"),
+            Some(Packet::new(PacketKind::Connect, "/", None, None))
+        );
+        assert_eq!(
+            Packet::parse("0/admin,{\"token\":\"123\"}"),
+            Some(Packet::new(
+                PacketKind::Connect,
+                "/admin",
+                None,
+                Some(json!({ "token": "123" }))
+            ))
+        );
+        assert_eq!(
+            Packet::parse("0/admin?v=1,"),
+            Some(Packet::new(PacketKind::Connect, "/admin", None, None))
+        );
+        assert_eq!(
+            Packet::parse("1/admin,"),
+            Some(Packet::new(PacketKind::Disconnect, "/admin", None, None))
+        );
+        assert_eq!(
+            Packet::parse("1/admin"),
+            Some(Packet::new(PacketKind::Disconnect, "/admin", None, None))
+        );
+    }
+
+    #[test]
+    fn parse_event_packets() {
+        assert_eq!(
+            Packet::parse("2[\"hello\",1]"),
+            Some(Packet::new(PacketKind::Event, "/", None, Some(json!(["hello", 1]))))
+        );
+        assert_eq!(
+            Packet::parse("2/chat,12[\"hello\"]"),
+            Some(Packet::new(
+                PacketKind::Event,
+                "/chat",
+                Some(12),
+                Some(json!(["hello"]))
+            ))
+        );
+        assert_eq!(
+            Packet::parse("312[\"ok\"]"),
+            Some(Packet::new(PacketKind::Ack, "/", Some(12), Some(json!(["ok"]))))
+        );
+        assert_eq!(
+            Packet::parse("4{\"message\":\"no\"}"),
+            Some(Packet::new(
+                PacketKind::ConnectError,
+                "/",
+                None,
+                Some(json!({ "message": "no" }))
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_packets() {
+        assert_eq!(Packet::parse(""), None);
+        // The binary events and acknowledgements are not supported.
+        assert_eq!(Packet::parse("51-[\"a\",{\"_placeholder\":true,\"num\":0}]"), None);
+        assert_eq!(Packet::parse("61-0[]"), None);
+        assert_eq!(Packet::parse("2[\"a\""), None);
+        assert_eq!(Packet::parse("299999999999999999999[]"), None);
+    }
+
+    #[test]
+    fn encode_packets() {
+        assert_eq!(
+            Packet::new(PacketKind::Connect, "/", None, Some(json!({ "sid": "a" }))).encode(),
+            "40{\"sid\":\"a\"}"
+        );
+        assert_eq!(
+            Packet::new(PacketKind::Event, "/chat", Some(3), Some(json!(["hi", 1]))).encode(),
+            "42/chat,3[\"hi\",1]"
+        );
+        assert_eq!(
+            Packet::new(PacketKind::Disconnect, "/chat", None, None).encode(),
+            "41/chat,"
+        );
+
+        let packet = Packet::new(PacketKind::Ack, "/chat", Some(7), Some(json!([{ "a": null }])));
+        assert_eq!(Packet::parse(&packet.encode()[1..]), Some(packet));
+    }
+
+    #[test]
+    fn event_arguments() {
+        assert_eq!(to_args(("a", 1)), Ok(vec![json!("a"), json!(1)]));
+        assert_eq!(to_args(vec![1, 2]), Ok(vec![json!(1), json!(2)]));
+        assert_eq!(to_args("a"), Ok(vec![json!("a")]));
+        assert_eq!(to_args(()), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn disconnect_the_slow_clients() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let slow = Arc::new(Notify::new());
+        let socket = Socket {
+            sid: new_sid(),
+            namespace: "/".to_owned(),
+            tx,
+            slow: slow.clone(),
+            connected: Arc::new(AtomicBool::new(true)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+            ack_timeout: Duration::from_secs(10),
+        };
+
+        assert_eq!(socket.emit("a", ()), Ok(()));
+        assert_eq!(socket.emit("b", ()), Err(EmitError::Disconnected));
+        assert!(!socket.connected());
+        assert!(slow.notified().now_or_never().is_some());
+
+        rx.try_recv().unwrap();
+        assert_eq!(socket.emit("c", ()), Err(EmitError::Disconnected));
+    }
+}
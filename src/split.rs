@@ -0,0 +1,443 @@
+use crate::message::close_reason;
+use crate::{CloseCode, Message, WebSocket};
+use futures::future::poll_fn;
+use futures::task::AtomicWaker;
+use futures::{ready, Sink, Stream};
+use hyper::{http::Extensions, upgrade::Upgraded};
+use std::borrow::Cow;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::task::{Context, Poll, Wake, Waker};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+/// The state shared by the halves of a split [`WebSocket`](./struct.WebSocket.html).
+///
+/// The socket is only locked while a half polls it. A half finding it locked waits for the other one to release it
+/// instead of blocking the thread, like the halves of `StreamExt::split`.
+struct Shared<S> {
+    ws: Mutex<WebSocket<S>>,
+    wakers: Arc<Wakers>,
+    waker: Waker,
+    remote_addr: SocketAddr,
+    client_addr: SocketAddr,
+    subprotocol: Option<String>,
+}
+
+/// The wakers of the tasks polling the halves.
+///
+/// The socket is polled with a waker waking both halves, so a half never replaces the waker registered by the other
+/// one, e.g. when the reader sends a close frame while the writer waits for the connection to be ready.
+struct Wakers {
+    reader: Waiter,
+    writer: Waiter,
+}
+
+struct Waiter {
+    waker: AtomicWaker,
+    locked_out: AtomicBool,
+}
+
+#[derive(Clone, Copy)]
+enum Half {
+    Reader,
+    Writer,
+}
+
+/// The socket locked by a half, released when dropped.
+struct Locked<'a, S> {
+    ws: Option<MutexGuard<'a, WebSocket<S>>>,
+    shared: &'a Shared<S>,
+    half: Half,
+}
+
+impl Wakers {
+    fn waiter(&self, half: Half) -> &Waiter {
+        match half {
+            Half::Reader => &self.reader,
+            Half::Writer => &self.writer,
+        }
+    }
+
+    fn other(&self, half: Half) -> &Waiter {
+        match half {
+            Half::Reader => &self.writer,
+            Half::Writer => &self.reader,
+        }
+    }
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.reader.waker.wake();
+        self.writer.waker.wake();
+    }
+}
+
+impl<S> Shared<S> {
+    /// Locks the socket for the half, or registers the half to be woken once the other one releases it.
+    fn poll_lock(&self, half: Half, cx: &mut Context) -> Poll<Locked<'_, S>> {
+        let waiter = self.wakers.waiter(half);
+        waiter.waker.register(cx.waker());
+
+        // The flag is raised before trying, so the other half either releases the lock before the attempt or sees the
+        // flag once it releases the lock.
+        waiter.locked_out.store(true, Ordering::SeqCst);
+        let ws = match self.ws.try_lock() {
+            Ok(ws) => ws,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return Poll::Pending,
+        };
+        waiter.locked_out.store(false, Ordering::SeqCst);
+
+        Poll::Ready(Locked {
+            ws: Some(ws),
+            shared: self,
+            half,
+        })
+    }
+
+    /// Locks the socket for the half, blocking the thread until the other half is done polling it.
+    fn lock(&self, half: Half) -> Locked<'_, S> {
+        Locked {
+            ws: Some(self.ws.lock().unwrap_or_else(PoisonError::into_inner)),
+            shared: self,
+            half,
+        }
+    }
+}
+
+impl<S> Locked<'_, S> {
+    fn ws(&mut self) -> &mut WebSocket<S> {
+        self.ws.as_mut().expect("the socket is locked until dropped")
+    }
+
+    /// Polls the socket with the waker of both halves.
+    fn poll<T>(&mut self, f: impl FnOnce(Pin<&mut WebSocket<S>>, &mut Context) -> T) -> T
+    where
+        S: Unpin,
+    {
+        let waker = self.shared.waker.clone();
+        f(Pin::new(self.ws()), &mut Context::from_waker(&waker))
+    }
+}
+
+impl<S> Drop for Locked<'_, S> {
+    fn drop(&mut self) {
+        drop(self.ws.take());
+
+        let other = self.shared.wakers.other(self.half);
+        if other.locked_out.swap(false, Ordering::SeqCst) {
+            other.waker.wake();
+        }
+    }
+}
+
+/// The receiving half of a [`WebSocket`](./struct.WebSocket.html), created by
+/// [`WebSocket::into_split`](./struct.WebSocket.html#method.into_split).
+///
+/// It implements the [`Stream`](https://docs.rs/futures/0.3.5/futures/stream/trait.Stream.html) trait.
+pub struct WebSocketReader<S = Upgraded> {
+    shared: Arc<Shared<S>>,
+}
+
+/// The sending half of a [`WebSocket`](./struct.WebSocket.html), created by
+/// [`WebSocket::into_split`](./struct.WebSocket.html#method.into_split).
+///
+/// It implements the [`Sink`](https://docs.rs/futures/0.3.5/futures/sink/trait.Sink.html) trait.
+pub struct WebSocketWriter<S = Upgraded> {
+    shared: Arc<Shared<S>>,
+    slot: Option<Message>,
+}
+
+/// The error returned when reuniting two halves which were not split from the same
+/// [`WebSocket`](./struct.WebSocket.html), or while the writer holds a message not flushed yet. The halves are given
+/// back.
+pub struct ReuniteError<S = Upgraded>(pub WebSocketReader<S>, pub WebSocketWriter<S>);
+
+pub(crate) fn split<S: AsyncRead + AsyncWrite + Unpin>(ws: WebSocket<S>) -> (WebSocketReader<S>, WebSocketWriter<S>) {
+    let wakers = Arc::new(Wakers {
+        reader: Waiter {
+            waker: AtomicWaker::new(),
+            locked_out: AtomicBool::new(false),
+        },
+        writer: Waiter {
+            waker: AtomicWaker::new(),
+            locked_out: AtomicBool::new(false),
+        },
+    });
+    let shared = Arc::new(Shared {
+        remote_addr: ws.remote_addr(),
+        client_addr: ws.client_addr(),
+        subprotocol: ws.subprotocol().map(ToOwned::to_owned),
+        ws: Mutex::new(ws),
+        waker: Waker::from(wakers.clone()),
+        wakers,
+    });
+    let reader = WebSocketReader { shared: shared.clone() };
+    (reader, WebSocketWriter { shared, slot: None })
+}
+
+fn reunite<S: AsyncRead + AsyncWrite + Unpin>(
+    reader: WebSocketReader<S>,
+    writer: WebSocketWriter<S>,
+) -> Result<WebSocket<S>, ReuniteError<S>> {
+    if !Arc::ptr_eq(&reader.shared, &writer.shared) || writer.slot.is_some() {
+        return Err(ReuniteError(reader, writer));
+    }
+    drop(writer);
+
+    let shared = Arc::try_unwrap(reader.shared)
+        .ok()
+        .expect("the halves are the only owners");
+    Ok(shared.ws.into_inner().unwrap_or_else(PoisonError::into_inner))
+}
+
+impl<S> WebSocketReader<S> {
+    /// Get the peer's remote address, see [`WebSocket::remote_addr`](./struct.WebSocket.html#method.remote_addr).
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.shared.remote_addr
+    }
+
+    /// Get the address of the TCP peer, see [`WebSocket::peer_addr`](./struct.WebSocket.html#method.peer_addr).
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.remote_addr
+    }
+
+    /// Get the client address, see [`WebSocket::client_addr`](./struct.WebSocket.html#method.client_addr).
+    pub fn client_addr(&self) -> SocketAddr {
+        self.shared.client_addr
+    }
+
+    /// Get the subprotocol selected during the handshake, if any.
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.shared.subprotocol.as_deref()
+    }
+
+    /// Calls the function with the [extensions](./struct.WebSocket.html#method.extensions) of the connection, which
+    /// are shared by both halves.
+    ///
+    /// The connection can't be polled by the other half while the function runs.
+    pub fn with_extensions<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Extensions) -> T,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        f(self.shared.lock(Half::Reader).ws().extensions_mut())
+    }
+
+    /// Puts the halves back together into the [`WebSocket`](./struct.WebSocket.html) they were split from.
+    ///
+    /// It fails if the halves were not split from the same `WebSocket`, or if the writer still holds a message, so
+    /// the writer must be flushed before.
+    pub fn reunite(self, writer: WebSocketWriter<S>) -> Result<WebSocket<S>, ReuniteError<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        reunite(self, writer)
+    }
+}
+
+impl<S> WebSocketWriter<S> {
+    /// Get the peer's remote address, see [`WebSocket::remote_addr`](./struct.WebSocket.html#method.remote_addr).
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.shared.remote_addr
+    }
+
+    /// Get the address of the TCP peer, see [`WebSocket::peer_addr`](./struct.WebSocket.html#method.peer_addr).
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.remote_addr
+    }
+
+    /// Get the client address, see [`WebSocket::client_addr`](./struct.WebSocket.html#method.client_addr).
+    pub fn client_addr(&self) -> SocketAddr {
+        self.shared.client_addr
+    }
+
+    /// Get the subprotocol selected during the handshake, if any.
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.shared.subprotocol.as_deref()
+    }
+
+    /// Calls the function with the [extensions](./struct.WebSocket.html#method.extensions) of the connection, which
+    /// are shared by both halves.
+    ///
+    /// The connection can't be polled by the other half while the function runs.
+    pub fn with_extensions<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Extensions) -> T,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        f(self.shared.lock(Half::Writer).ws().extensions_mut())
+    }
+
+    /// Puts the halves back together into the [`WebSocket`](./struct.WebSocket.html) they were split from.
+    ///
+    /// It fails if the halves were not split from the same `WebSocket`, or if the writer still holds a message, so
+    /// the writer must be flushed before.
+    pub fn reunite(self, reader: WebSocketReader<S>) -> Result<WebSocket<S>, ReuniteError<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        reunite(reader, self)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketWriter<S> {
+    /// Gracefully closes the connection with a code and reason.
+    ///
+    /// The [`WebSocketReader`](./struct.WebSocketReader.html) keeps receiving the messages sent by the peer until it
+    /// acknowledges the close.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use routerify_websocket::{CloseCode, WebSocket};
+    ///
+    /// async fn ws_handler(ws: WebSocket) {
+    ///     let (mut reader, mut writer) = ws.into_split();
+    ///
+    ///     tokio::spawn(async move {
+    ///         tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+    ///         writer.close_with(CloseCode::Away, "Shutting down").await.unwrap();
+    ///     });
+    ///
+    ///     while let Some(Ok(msg)) = reader.next().await {
+    ///         println!("{} sent {:?}", reader.remote_addr(), msg);
+    ///     }
+    /// }
+    /// ```
+    pub async fn close_with<R: Into<Cow<'static, str>>>(&mut self, code: CloseCode, reason: R) -> crate::Result<()> {
+        let mut frame = Some(CloseFrame {
+            code,
+            reason: close_reason(reason.into()),
+        });
+        poll_fn(|cx| {
+            let mut ws = ready!(self.shared.poll_lock(Half::Writer, cx));
+            if let Some(frame) = frame.take() {
+                ws.ws().queue_close(frame);
+            }
+            ws.poll(|ws, cx| ws.get_mut().poll_pending_close(cx))
+        })
+        .await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for WebSocketReader<S> {
+    type Item = Result<Message, crate::WebsocketError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut ws = ready!(self.shared.poll_lock(Half::Reader, cx));
+        ws.poll(|ws, cx| ws.poll_next(cx))
+    }
+}
+
+/// Sends the message held by the writer, once the connection is ready for it.
+fn poll_send_slot<S: AsyncRead + AsyncWrite + Unpin>(
+    slot: &mut Option<Message>,
+    ws: &mut Locked<'_, S>,
+) -> Poll<Result<(), crate::WebsocketError>> {
+    if slot.is_some() {
+        ready!(ws.poll(|ws, cx| ws.poll_ready(cx)))?;
+        let msg = slot.take().expect("the slot holds a message");
+        Pin::new(ws.ws()).start_send(msg)?;
+    }
+    Poll::Ready(Ok(()))
+}
+
+/// The messages are held by the writer until they are flushed, so sending one never waits for the reader to release
+/// the connection.
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<Message> for WebSocketWriter<S> {
+    type Error = crate::WebsocketError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.slot.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        let mut ws = ready!(this.shared.poll_lock(Half::Writer, cx));
+        poll_send_slot(&mut this.slot, &mut ws)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.get_mut().slot = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        let mut ws = ready!(this.shared.poll_lock(Half::Writer, cx));
+        ready!(poll_send_slot(&mut this.slot, &mut ws))?;
+        ws.poll(|ws, cx| ws.poll_flush(cx))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        let mut ws = ready!(this.shared.poll_lock(Half::Writer, cx));
+        ready!(poll_send_slot(&mut this.slot, &mut ws))?;
+        ws.poll(|ws, cx| ws.poll_close(cx))
+    }
+}
+
+impl<S> fmt::Debug for WebSocketReader<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("WebSocketReader").finish()
+    }
+}
+
+impl<S> fmt::Debug for WebSocketWriter<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("WebSocketWriter").finish()
+    }
+}
+
+impl<S> fmt::Debug for ReuniteError<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_tuple("ReuniteError").finish()
+    }
+}
+
+impl<S> Display for ReuniteError<S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Tried to reunite halves that are not from the same websocket, or before the writer was flushed"
+        )
+    }
+}
+
+impl<S> std::error::Error for ReuniteError<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Role, WebSocketConfig};
+    use futures::{SinkExt, StreamExt};
+
+    #[tokio::test]
+    async fn reunite_after_flushing_the_writer() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let (server, client) = tokio::io::duplex(1024);
+        let server = WebSocket::from_stream(server, Role::Server, WebSocketConfig::default(), addr).await;
+        let mut client = WebSocket::from_stream(client, Role::Client, WebSocketConfig::default(), addr).await;
+
+        let (reader, mut writer) = server.into_split();
+        writer.feed(Message::text("Hello world")).await.unwrap();
+        let ReuniteError(reader, mut writer) = reader.reunite(writer).unwrap_err();
+
+        writer.flush().await.unwrap();
+        let mut server = reader.reunite(writer).unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("Hello world"));
+
+        server.send(Message::text("Hello again")).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("Hello again"));
+    }
+}
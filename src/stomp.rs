@@ -0,0 +1,914 @@
+//! The [STOMP 1.2](https://stomp.github.io/stomp-specification-1.2.html) frame codec and an in-memory broker.
+//!
+//! The [`Frame`](./struct.Frame.html)s are carried in websocket messages, one frame per message. The
+//! [`StompBroker`](./struct.StompBroker.html) routes the `SEND` frames to the subscribers of their destination, so a
+//! route can act as a STOMP endpoint with [`upgrade_stomp`](./fn.upgrade_stomp.html). The `v12.stomp` subprotocol is
+//! selected if the client offers it.
+//!
+//! The broker supports the `CONNECT`/`STOMP`, `SEND`, `SUBSCRIBE`, `UNSUBSCRIBE`, `ACK`, `NACK`, `BEGIN`, `COMMIT`,
+//! `ABORT` and `DISCONNECT` frames, the receipts and the heart-beat negotiation. The messages are not persisted, so
+//! the `NACK`ed messages are not redelivered.
+//!
+//! # Optional
+//!
+//! This requires the optional `stomp` feature to be enabled.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use hyper::Body;
+//! # use routerify::Router;
+//! use routerify_websocket::stomp::{upgrade_stomp, StompBroker};
+//! # use std::convert::Infallible;
+//! use std::time::Duration;
+//!
+//! fn router() -> Router<Body, Infallible> {
+//!     let broker = StompBroker::new().heart_beat(Duration::from_secs(10), Duration::from_secs(10));
+//!
+//!     // The application can publish to the subscribers too.
+//!     let publisher = broker.clone();
+//!     tokio::spawn(async move {
+//!         loop {
+//!             tokio::time::sleep(Duration::from_secs(1)).await;
+//!             publisher.publish("/topic/ticks", "tick", Vec::new());
+//!         }
+//!     });
+//!
+//!     Router::builder()
+//!         .any_method("/stomp", upgrade_stomp(broker))
+//!         .build()
+//!         .unwrap()
+//! }
+//! ```
+
+use crate::upgrade::upgrade_with;
+use crate::{Message, UpgradeConfig, WebSocket, WebSocketConfig};
+use futures::future::{poll_fn, Ready};
+use futures::{SinkExt, StreamExt};
+use hyper::{body::HttpBody, Request, Response};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Notify;
+use tokio::time::{Instant, Interval, Sleep};
+
+/// The name of the subprotocol.
+pub const PROTOCOL: &str = "v12.stomp";
+
+/// A STOMP command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    /// Client: opens the session.
+    Connect,
+    /// Client: opens the session, same as `CONNECT`.
+    Stomp,
+    /// Server: the session is opened.
+    Connected,
+    /// Client: sends a message to a destination.
+    Send,
+    /// Client: subscribes to a destination.
+    Subscribe,
+    /// Client: unsubscribes from a destination.
+    Unsubscribe,
+    /// Client: acknowledges a message.
+    Ack,
+    /// Client: rejects a message.
+    Nack,
+    /// Client: starts a transaction.
+    Begin,
+    /// Client: commits a transaction.
+    Commit,
+    /// Client: aborts a transaction.
+    Abort,
+    /// Client: closes the session.
+    Disconnect,
+    /// Server: a message delivered to a subscription.
+    Message,
+    /// Server: a frame requesting a receipt was processed.
+    Receipt,
+    /// Server: an error occurred, the connection is closed afterwards.
+    Error,
+}
+
+impl Command {
+    /// Returns the command name as it appears in the frames.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Command::Connect => "CONNECT",
+            Command::Stomp => "STOMP",
+            Command::Connected => "CONNECTED",
+            Command::Send => "SEND",
+            Command::Subscribe => "SUBSCRIBE",
+            Command::Unsubscribe => "UNSUBSCRIBE",
+            Command::Ack => "ACK",
+            Command::Nack => "NACK",
+            Command::Begin => "BEGIN",
+            Command::Commit => "COMMIT",
+            Command::Abort => "ABORT",
+            Command::Disconnect => "DISCONNECT",
+            Command::Message => "MESSAGE",
+            Command::Receipt => "RECEIPT",
+            Command::Error => "ERROR",
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = FrameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "CONNECT" => Command::Connect,
+            "STOMP" => Command::Stomp,
+            "CONNECTED" => Command::Connected,
+            "SEND" => Command::Send,
+            "SUBSCRIBE" => Command::Subscribe,
+            "UNSUBSCRIBE" => Command::Unsubscribe,
+            "ACK" => Command::Ack,
+            "NACK" => Command::Nack,
+            "BEGIN" => Command::Begin,
+            "COMMIT" => Command::Commit,
+            "ABORT" => Command::Abort,
+            "DISCONNECT" => Command::Disconnect,
+            "MESSAGE" => Command::Message,
+            "RECEIPT" => Command::Receipt,
+            "ERROR" => Command::Error,
+            _ => return Err(FrameError::new(format!("Unknown command: {}", s))),
+        })
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error while parsing a STOMP frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameError {
+    message: String,
+}
+
+impl FrameError {
+    fn new<M: Into<String>>(message: M) -> Self {
+        FrameError {
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid STOMP frame: {}", self.message)
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// A STOMP frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    command: Command,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Frame {
+    /// Creates a new frame without headers and body.
+    pub fn new(command: Command) -> Self {
+        Frame {
+            command,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Adds a header to the frame.
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the body of the frame.
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// The frame command.
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// The frame headers, in order.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The value of a header. If a header is repeated, only the first value is significant.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The frame body.
+    pub fn get_body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Parses a frame, returning `None` for a heart-beat.
+    pub fn parse(data: &[u8]) -> Result<Option<Frame>, FrameError> {
+        let data = trim_leading_eols(data);
+        if data.is_empty() {
+            return Ok(None);
+        }
+
+        let (command, mut rest) = split_line(data).ok_or_else(|| FrameError::new("Missing command"))?;
+        let command = std::str::from_utf8(command)
+            .map_err(|_| FrameError::new("The command is not UTF-8"))?
+            .parse::<Command>()?;
+        // The headers of the frames opening the session are not escaped, for compatibility with STOMP 1.0.
+        let escaped = !matches!(command, Command::Connect | Command::Stomp | Command::Connected);
+
+        let mut headers = Vec::new();
+        loop {
+            let (line, remaining) = split_line(rest).ok_or_else(|| FrameError::new("Missing the end of headers"))?;
+            rest = remaining;
+            if line.is_empty() {
+                break;
+            }
+
+            let line = std::str::from_utf8(line).map_err(|_| FrameError::new("A header is not UTF-8"))?;
+            let mut parts = line.splitn(2, ':');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err(FrameError::new(format!("Invalid header: {}", line))),
+            };
+            if escaped {
+                headers.push((unescape(name)?, unescape(value)?));
+            } else {
+                headers.push((name.to_owned(), value.to_owned()));
+            }
+        }
+
+        let frame = Frame {
+            command,
+            headers,
+            body: Vec::new(),
+        };
+        let len = match frame.get_header("content-length") {
+            Some(len) => len
+                .parse::<usize>()
+                .map_err(|_| FrameError::new("Invalid content-length header"))?,
+            None => rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| FrameError::new("Missing the NULL octet"))?,
+        };
+        if rest.len() <= len || rest[len] != 0 {
+            return Err(FrameError::new("The body is not terminated by a NULL octet"));
+        }
+
+        Ok(Some(Frame {
+            body: rest[..len].to_vec(),
+            ..frame
+        }))
+    }
+
+    /// Serializes the frame.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let escaped = !matches!(self.command, Command::Connect | Command::Stomp | Command::Connected);
+        let mut out = Vec::with_capacity(self.body.len() + 64);
+
+        out.extend_from_slice(self.command.as_str().as_bytes());
+        out.push(b'\n');
+        for (name, value) in &self.headers {
+            if escaped {
+                out.extend_from_slice(escape(name).as_bytes());
+                out.push(b':');
+                out.extend_from_slice(escape(value).as_bytes());
+            } else {
+                out.extend_from_slice(name.as_bytes());
+                out.push(b':');
+                out.extend_from_slice(value.as_bytes());
+            }
+            out.push(b'\n');
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.body);
+        out.push(0);
+
+        out
+    }
+
+    /// Converts the frame to a websocket message, a `Text` message unless the frame is not UTF-8.
+    pub fn to_message(&self) -> Message {
+        match String::from_utf8(self.to_bytes()) {
+            Ok(text) => Message::text(text),
+            Err(err) => Message::binary(err.into_bytes()),
+        }
+    }
+
+    /// Parses a frame from a websocket message, returning `None` for a heart-beat.
+    pub fn from_message(msg: &Message) -> Result<Option<Frame>, FrameError> {
+        Frame::parse(msg.as_bytes())
+    }
+}
+
+fn trim_leading_eols(mut data: &[u8]) -> &[u8] {
+    while let Some(rest) = data.strip_prefix(b"\r\n").or_else(|| data.strip_prefix(b"\n")) {
+        data = rest;
+    }
+    data
+}
+
+/// Splits the first line, ended by `\n` or `\r\n`.
+fn split_line(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = data.iter().position(|&b| b == b'\n')?;
+    let line = &data[..end];
+    Some((line.strip_suffix(b"\r").unwrap_or(line), &data[end + 1..]))
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            ':' => out.push_str("\\c"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Result<String, FrameError> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some('c') => out.push(':'),
+            _ => return Err(FrameError::new("Invalid escape sequence in a header")),
+        }
+    }
+    Ok(out)
+}
+
+/// The acknowledgment mode of a subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AckMode {
+    Auto,
+    Client,
+    ClientIndividual,
+}
+
+struct Subscriber {
+    session: u64,
+    id: String,
+    ack: AckMode,
+    tx: mpsc::Sender<Frame>,
+    slow: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    destinations: HashMap<String, Vec<Subscriber>>,
+    next_session: AtomicU64,
+    next_message: AtomicU64,
+}
+
+/// An in-memory STOMP broker delivering the messages sent to a destination to all of its subscribers.
+///
+/// The clones of a broker share the same destinations.
+#[derive(Clone)]
+pub struct StompBroker {
+    heart_beat: (Duration, Duration),
+    max_queued: usize,
+    state: Arc<Mutex<BrokerState>>,
+}
+
+impl StompBroker {
+    /// Creates a new broker, without heart-beats.
+    ///
+    /// At most `1024` messages are queued for a session by default.
+    pub fn new() -> Self {
+        StompBroker {
+            heart_beat: (Duration::from_secs(0), Duration::from_secs(0)),
+            max_queued: 1024,
+            state: Arc::new(Mutex::new(BrokerState::default())),
+        }
+    }
+
+    /// Sets the heart-beats the server can send and wants to receive, zero meaning none.
+    ///
+    /// The actual intervals are negotiated with each client on `CONNECT`. A client not sending anything for twice the
+    /// negotiated interval is disconnected.
+    pub fn heart_beat(mut self, send: Duration, receive: Duration) -> Self {
+        self.heart_beat = (send, receive);
+        self
+    }
+
+    /// Sets the maximum number of messages queued for a session while they are sent to the client.
+    ///
+    /// A client not reading its messages fast enough to keep up with the publishers is sent an `ERROR` frame and
+    /// disconnected once the limit is reached, so it never holds back the publishers or the other subscribers.
+    pub fn max_queued(mut self, max: usize) -> Self {
+        self.max_queued = max.max(1);
+        self
+    }
+
+    /// Publishes a message to the subscribers of a destination, returning the number of subscribers it was delivered to.
+    ///
+    /// The subscribers whose queue is full are not delivered the message and get disconnected.
+    pub fn publish<B: Into<Vec<u8>>>(&self, destination: &str, body: B, headers: Vec<(String, String)>) -> usize {
+        let body = body.into();
+        let state = self.state.lock().unwrap();
+
+        let subscribers = match state.destinations.get(destination) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+
+        let mut delivered = 0;
+        for subscriber in subscribers {
+            let message_id = state.next_message.fetch_add(1, Ordering::Relaxed).to_string();
+            let mut frame = Frame::new(Command::Message)
+                .header("subscription", subscriber.id.clone())
+                .header("message-id", message_id.clone())
+                .header("destination", destination);
+            if subscriber.ack != AckMode::Auto {
+                frame = frame.header("ack", message_id);
+            }
+            for (name, value) in &headers {
+                if !matches!(
+                    name.as_str(),
+                    "subscription" | "message-id" | "destination" | "ack" | "receipt"
+                ) {
+                    frame = frame.header(name.clone(), value.clone());
+                }
+            }
+
+            match subscriber.tx.try_send(frame.body(body.clone())) {
+                Ok(()) => delivered += 1,
+                Err(TrySendError::Full(_)) => subscriber.slow.notify_one(),
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+
+        delivered
+    }
+
+    fn subscribe(&self, destination: String, subscriber: Subscriber) {
+        let mut state = self.state.lock().unwrap();
+        state.destinations.entry(destination).or_default().push(subscriber);
+    }
+
+    fn unsubscribe(&self, session: u64, id: &str) {
+        self.retain(|subscriber| !(subscriber.session == session && subscriber.id == id));
+    }
+
+    fn remove_session(&self, session: u64) {
+        self.retain(|subscriber| subscriber.session != session);
+    }
+
+    fn retain<F: Fn(&Subscriber) -> bool>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        for subscribers in state.destinations.values_mut() {
+            subscribers.retain(&f);
+        }
+        state.destinations.retain(|_, subscribers| !subscribers.is_empty());
+    }
+}
+
+impl Default for StompBroker {
+    fn default() -> Self {
+        StompBroker::new()
+    }
+}
+
+impl fmt::Debug for StompBroker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StompBroker")
+            .field("heart_beat", &self.heart_beat)
+            .field("max_queued", &self.max_queued)
+            .finish()
+    }
+}
+
+/// Removes the subscriptions of a session from the broker when it ends.
+struct Session {
+    id: u64,
+    broker: StompBroker,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.broker.remove_session(self.id);
+    }
+}
+
+enum Event {
+    Incoming(Option<crate::Result<Message>>),
+    Deliver(Frame),
+    SendHeartBeat,
+    ReceiveTimeout,
+    SlowConsumer,
+}
+
+/// Serves a STOMP session on an upgraded websocket connection with the broker, until it is closed.
+pub async fn serve(ws: WebSocket, broker: StompBroker) -> crate::Result<()> {
+    let mut ws = ws;
+    let (tx, mut rx) = mpsc::channel::<Frame>(broker.max_queued);
+    let slow = Arc::new(Notify::new());
+    let mut slow_consumer = std::pin::pin!(slow.notified());
+    let session = Session {
+        id: broker
+            .state
+            .lock()
+            .unwrap()
+            .next_session
+            .fetch_add(1, Ordering::Relaxed),
+        broker: broker.clone(),
+    };
+    let mut connected = false;
+    let mut transactions = HashMap::<String, Vec<Frame>>::new();
+    let mut send_interval: Option<Interval> = None;
+    let mut receive_timeout: Option<Duration> = None;
+    let mut receive_deadline: Option<Pin<Box<Sleep>>> = None;
+
+    loop {
+        let event = poll_fn(|cx| {
+            if let Some(ref mut deadline) = receive_deadline {
+                if deadline.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Event::ReceiveTimeout);
+                }
+            }
+            if let Some(ref mut interval) = send_interval {
+                if interval.poll_tick(cx).is_ready() {
+                    return Poll::Ready(Event::SendHeartBeat);
+                }
+            }
+            if slow_consumer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Event::SlowConsumer);
+            }
+            if let Poll::Ready(Some(frame)) = rx.poll_recv(cx) {
+                return Poll::Ready(Event::Deliver(frame));
+            }
+            ws.poll_next_unpin(cx).map(Event::Incoming)
+        })
+        .await;
+
+        let msg = match event {
+            Event::Deliver(frame) => {
+                ws.send(frame.to_message()).await?;
+                continue;
+            }
+            Event::SendHeartBeat => {
+                ws.send(Message::text("\n")).await?;
+                continue;
+            }
+            Event::ReceiveTimeout => return send_error(ws, "Heart-beat timeout", None).await,
+            Event::SlowConsumer => return send_error(ws, "Too many messages queued for the session", None).await,
+            Event::Incoming(Some(Ok(msg))) => msg,
+            Event::Incoming(Some(Err(err))) => return Err(err),
+            Event::Incoming(None) => return Ok(()),
+        };
+
+        if let (Some(timeout), Some(deadline)) = (receive_timeout, receive_deadline.as_mut()) {
+            deadline.as_mut().reset(Instant::now() + timeout);
+        }
+        if !msg.is_text() && !msg.is_binary() {
+            continue;
+        }
+
+        let frame = match Frame::from_message(&msg) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => continue,
+            Err(err) => return send_error(ws, &err.to_string(), None).await,
+        };
+        let receipt = frame.get_header("receipt").map(ToOwned::to_owned);
+
+        if !connected {
+            if !matches!(frame.command, Command::Connect | Command::Stomp) {
+                return send_error(ws, "The session is not connected", receipt).await;
+            }
+            let versions = frame.get_header("accept-version").unwrap_or("1.0");
+            if !versions.split(',').any(|version| version.trim() == "1.2") {
+                return send_error(ws, "Only the STOMP 1.2 protocol is supported", receipt).await;
+            }
+
+            let (client_send, client_receive) = parse_heart_beat(frame.get_header("heart-beat"));
+            let (server_send, server_receive) = broker.heart_beat;
+            if !server_send.is_zero() && !client_receive.is_zero() {
+                let period = server_send.max(client_receive);
+                send_interval = Some(tokio::time::interval_at(Instant::now() + period, period));
+            }
+            if !server_receive.is_zero() && !client_send.is_zero() {
+                let timeout = server_receive.max(client_send) * 2;
+                receive_timeout = Some(timeout);
+                receive_deadline = Some(Box::pin(tokio::time::sleep(timeout)));
+            }
+
+            let connected_frame = Frame::new(Command::Connected)
+                .header("version", "1.2")
+                .header("session", session.id.to_string())
+                .header(
+                    "heart-beat",
+                    format!("{},{}", server_send.as_millis(), server_receive.as_millis()),
+                );
+            ws.send(connected_frame.to_message()).await?;
+            connected = true;
+            continue;
+        }
+
+        if let Some(transaction) = frame.get_header("transaction") {
+            if matches!(frame.command, Command::Send | Command::Ack | Command::Nack) {
+                match transactions.get_mut(transaction) {
+                    Some(frames) => frames.push(frame),
+                    None => return send_error(ws, "Unknown transaction", receipt).await,
+                }
+                send_receipt(&mut ws, receipt).await?;
+                continue;
+            }
+        }
+
+        match frame.command {
+            Command::Send => {
+                let destination = match frame.get_header("destination") {
+                    Some(destination) => destination.to_owned(),
+                    None => return send_error(ws, "Missing the destination header", receipt).await,
+                };
+                deliver(&broker, &destination, frame);
+            }
+            Command::Subscribe => {
+                let (id, destination) = match (frame.get_header("id"), frame.get_header("destination")) {
+                    (Some(id), Some(destination)) => (id.to_owned(), destination.to_owned()),
+                    _ => return send_error(ws, "Missing the id or destination header", receipt).await,
+                };
+                let ack = match frame.get_header("ack").unwrap_or("auto") {
+                    "auto" => AckMode::Auto,
+                    "client" => AckMode::Client,
+                    "client-individual" => AckMode::ClientIndividual,
+                    _ => return send_error(ws, "Invalid ack header", receipt).await,
+                };
+                broker.subscribe(
+                    destination,
+                    Subscriber {
+                        session: session.id,
+                        id,
+                        ack,
+                        tx: tx.clone(),
+                        slow: slow.clone(),
+                    },
+                );
+            }
+            Command::Unsubscribe => match frame.get_header("id") {
+                Some(id) => broker.unsubscribe(session.id, id),
+                None => return send_error(ws, "Missing the id header", receipt).await,
+            },
+            // The messages are not persisted, so there is nothing to do on acknowledgment.
+            Command::Ack | Command::Nack => {}
+            Command::Begin => match frame.get_header("transaction") {
+                Some(transaction) => {
+                    transactions.insert(transaction.to_owned(), Vec::new());
+                }
+                None => return send_error(ws, "Missing the transaction header", receipt).await,
+            },
+            Command::Commit | Command::Abort => {
+                let frames = match frame.get_header("transaction").and_then(|tx| transactions.remove(tx)) {
+                    Some(frames) => frames,
+                    None => return send_error(ws, "Unknown transaction", receipt).await,
+                };
+                if frame.command == Command::Commit {
+                    for frame in frames.into_iter().filter(|frame| frame.command == Command::Send) {
+                        if let Some(destination) = frame.get_header("destination").map(ToOwned::to_owned) {
+                            deliver(&broker, &destination, frame);
+                        }
+                    }
+                }
+            }
+            Command::Disconnect => {
+                send_receipt(&mut ws, receipt).await?;
+                return ws.close().await;
+            }
+            _ => return send_error(ws, "Unexpected frame", receipt).await,
+        }
+
+        send_receipt(&mut ws, receipt).await?;
+    }
+}
+
+fn deliver(broker: &StompBroker, destination: &str, frame: Frame) {
+    let headers = frame
+        .headers
+        .into_iter()
+        .filter(|(name, _)| name != "transaction")
+        .collect();
+    broker.publish(destination, frame.body, headers);
+}
+
+fn parse_heart_beat(header: Option<&str>) -> (Duration, Duration) {
+    let mut parts = header
+        .unwrap_or("0,0")
+        .split(',')
+        .map(|part| part.trim().parse::<u64>().unwrap_or(0));
+    let send = parts.next().unwrap_or(0);
+    let receive = parts.next().unwrap_or(0);
+    (Duration::from_millis(send), Duration::from_millis(receive))
+}
+
+async fn send_receipt(ws: &mut WebSocket, receipt: Option<String>) -> crate::Result<()> {
+    match receipt {
+        Some(receipt) => {
+            ws.send(Frame::new(Command::Receipt).header("receipt-id", receipt).to_message())
+                .await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Sends an `ERROR` frame and closes the connection.
+async fn send_error(ws: WebSocket, message: &str, receipt: Option<String>) -> crate::Result<()> {
+    let mut ws = ws;
+    let mut frame = Frame::new(Command::Error).header("message", message);
+    if let Some(receipt) = receipt {
+        frame = frame.header("receipt-id", receipt);
+    }
+
+    ws.send(frame.to_message()).await?;
+    ws.close().await
+}
+
+/// Upgrades the http requests to websocket and serves them as STOMP sessions of the provided broker, with the
+/// provided config.
+///
+/// The config can either be an [`UpgradeConfig`](../struct.UpgradeConfig.html) or a plain [`WebSocketConfig`](../struct.WebSocketConfig.html).
+/// The subprotocols set on the config are replaced by `v12.stomp`.
+pub fn upgrade_stomp_with_config<B, E, C>(
+    broker: StompBroker,
+    config: C,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+    C: Into<UpgradeConfig>,
+{
+    upgrade_with(
+        move |ws| {
+            let broker = broker.clone();
+            async move {
+                if let Err(err) = serve(ws, broker).await {
+                    log::error!("{}", err);
+                }
+            }
+        },
+        config.into().subprotocols([PROTOCOL]),
+    )
+}
+
+/// Upgrades the http requests to websocket and serves them as STOMP sessions of the provided broker.
+pub fn upgrade_stomp<B, E>(
+    broker: StompBroker,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    upgrade_stomp_with_config(broker, WebSocketConfig::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8]) -> Frame {
+        Frame::parse(data).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parse_frame() {
+        let frame = parse(b"SEND\ndestination:/queue/a\ncontent-type:text/plain\n\nhello\0");
+
+        assert_eq!(frame.command(), Command::Send);
+        assert_eq!(
+            frame.headers(),
+            &[
+                ("destination".to_owned(), "/queue/a".to_owned()),
+                ("content-type".to_owned(), "text/plain".to_owned()),
+            ]
+        );
+        assert_eq!(frame.get_body(), b"hello");
+    }
+
+    #[test]
+    fn parse_heart_beats() {
+        assert_eq!(Frame::parse(b""), Ok(None));
+        assert_eq!(Frame::parse(b"\n"), Ok(None));
+        assert_eq!(Frame::parse(b"\r\n\n"), Ok(None));
+
+        let frame = parse(b"\n\r\nDISCONNECT\nreceipt:77\n\n\0");
+        assert_eq!(frame.command(), Command::Disconnect);
+        assert_eq!(frame.get_header("receipt"), Some("77"));
+    }
+
+    #[test]
+    fn parse_crlf_lines() {
+        let frame = parse(b"SUBSCRIBE\r\nid:0\r\ndestination:/topic/a\r\n\r\n\0");
+
+        assert_eq!(frame.command(), Command::Subscribe);
+        assert_eq!(frame.get_header("id"), Some("0"));
+        assert_eq!(frame.get_header("destination"), Some("/topic/a"));
+        assert!(frame.get_body().is_empty());
+    }
+
+    #[test]
+    fn parse_repeated_header() {
+        let frame = parse(b"MESSAGE\nfoo:World\nfoo:Hello\n\n\0");
+
+        assert_eq!(frame.get_header("foo"), Some("World"));
+        assert_eq!(frame.headers().len(), 2);
+    }
+
+    #[test]
+    fn parse_escaped_headers() {
+        let frame = parse(b"SEND\na\\cb:c\\\\d\\ne\\rf\n\n\0");
+        assert_eq!(frame.get_header("a:b"), Some("c\\d\ne\rf"));
+
+        // The headers of the frames opening the session are not escaped.
+        let frame = parse(b"CONNECT\nlogin:a\\cb\npasscode:x:y\n\n\0");
+        assert_eq!(frame.get_header("login"), Some("a\\cb"));
+        assert_eq!(frame.get_header("passcode"), Some("x:y"));
+
+        assert!(Frame::parse(b"SEND\na:b\\t\n\n\0").is_err());
+    }
+
+    #[test]
+    fn parse_content_length() {
+        let frame = parse(b"SEND\ncontent-length:5\n\na\0b\0c\0");
+        assert_eq!(frame.get_body(), b"a\0b\0c");
+
+        assert!(Frame::parse(b"SEND\ncontent-length:3\n\nabcd\0").is_err());
+        assert!(Frame::parse(b"SEND\ncontent-length:10\n\nabc\0").is_err());
+        assert!(Frame::parse(b"SEND\ncontent-length:abc\n\nabc\0").is_err());
+    }
+
+    #[test]
+    fn parse_invalid_frames() {
+        assert!(Frame::parse(b"SEND").is_err());
+        assert!(Frame::parse(b"FOO\n\n\0").is_err());
+        assert!(Frame::parse(b"SEND\ndestination:/a\n").is_err());
+        assert!(Frame::parse(b"SEND\ninvalid\n\n\0").is_err());
+        assert!(Frame::parse(b"SEND\n\nbody").is_err());
+        assert!(Frame::parse(b"\xff\n\n\0").is_err());
+    }
+
+    #[test]
+    fn serialize_frame() {
+        let frame = Frame::new(Command::Message)
+            .header("subscription", "0")
+            .header("a:b", "c\nd")
+            .body("hello");
+
+        assert_eq!(
+            frame.to_bytes(),
+            b"MESSAGE\nsubscription:0\na\\cb:c\\nd\n\nhello\0".to_vec()
+        );
+        assert_eq!(Frame::parse(&frame.to_bytes()), Ok(Some(frame)));
+
+        let frame = Frame::new(Command::Connected)
+            .header("version", "1.2")
+            .header("server", "a:b");
+        assert_eq!(frame.to_bytes(), b"CONNECTED\nversion:1.2\nserver:a:b\n\n\0".to_vec());
+    }
+
+    #[test]
+    fn parse_heart_beat_header() {
+        assert_eq!(
+            parse_heart_beat(Some("1000, 2000")),
+            (Duration::from_secs(1), Duration::from_secs(2))
+        );
+        assert_eq!(parse_heart_beat(None), (Duration::ZERO, Duration::ZERO));
+        assert_eq!(
+            parse_heart_beat(Some("x,500")),
+            (Duration::ZERO, Duration::from_millis(500))
+        );
+    }
+}
@@ -0,0 +1,104 @@
+#![cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+
+use crate::metrics::Direction;
+use crate::WebsocketError;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio_tungstenite::tungstenite::protocol;
+#[cfg(feature = "tracing")]
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The `tracing` span of a websocket connection, a no-op unless the `tracing` feature is enabled.
+#[derive(Clone)]
+pub(crate) struct ConnectionSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl ConnectionSpan {
+    pub(crate) fn new(remote_addr: SocketAddr, path: &str, subprotocol: Option<&str>) -> Self {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+
+        ConnectionSpan {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "websocket",
+                connection_id = id,
+                remote_addr = %remote_addr,
+                path = path,
+                subprotocol = subprotocol,
+            ),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn instrument<F: Future>(self, fut: F) -> tracing::instrument::Instrumented<F> {
+        tracing::Instrument::instrument(fut, self.span)
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn instrument<F: Future>(self, fut: F) -> F {
+        fut
+    }
+
+    pub(crate) fn handshake(&self) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(parent: &self.span, "websocket handshake completed");
+    }
+
+    pub(crate) fn message(&self, direction: Direction, msg: &protocol::Message) {
+        #[cfg(feature = "tracing")]
+        {
+            let kind = match msg {
+                protocol::Message::Text(_) => "text",
+                protocol::Message::Binary(_) => "binary",
+                protocol::Message::Ping(_) => "ping",
+                protocol::Message::Pong(_) => "pong",
+                protocol::Message::Close(_) => "close",
+                protocol::Message::Frame(_) => "frame",
+            };
+
+            match direction {
+                Direction::In => {
+                    tracing::debug!(parent: &self.span, kind, len = msg.len(), "websocket message received")
+                }
+                Direction::Out => tracing::debug!(parent: &self.span, kind, len = msg.len(), "websocket message sent"),
+            }
+
+            if let protocol::Message::Close(ref frame) = msg {
+                self.close(direction, frame.as_ref());
+            }
+        }
+    }
+
+    pub(crate) fn fragment(&self, direction: Direction, text: bool, len: usize) {
+        #[cfg(feature = "tracing")]
+        {
+            let kind = if text { "text" } else { "binary" };
+
+            match direction {
+                Direction::In => tracing::trace!(parent: &self.span, kind, len, "websocket message fragment received"),
+                Direction::Out => tracing::trace!(parent: &self.span, kind, len, "websocket message fragment sent"),
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn close(&self, direction: Direction, frame: Option<&CloseFrame>) {
+        let code = frame.map(|frame| u16::from(frame.code));
+        let reason = frame.map(|frame| frame.reason.as_ref()).unwrap_or("");
+
+        match direction {
+            Direction::In => tracing::info!(parent: &self.span, ?code, reason, "websocket close frame received"),
+            Direction::Out => tracing::info!(parent: &self.span, ?code, reason, "websocket close frame sent"),
+        }
+    }
+
+    pub(crate) fn error(&self, err: &WebsocketError) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(parent: &self.span, error = %err, "websocket error");
+    }
+}
@@ -1,15 +1,23 @@
-use crate::{WebSocket, WebSocketConfig};
-use futures::future::{ok, Ready};
+use crate::limit::ConnectionPermit;
+use crate::metrics;
+use crate::trace::ConnectionSpan;
+use crate::{Role, UpgradeConfig, WebSocket, WebSocketConfig};
+use futures::future::{ok, Either, Ready};
 use headers::{Connection, Header, SecWebsocketAccept, SecWebsocketKey, Upgrade};
 use hyper::{
     body::HttpBody,
     header::{self, HeaderValue},
-    Request, Response, StatusCode,
+    http::Extensions,
+    Method, Request, Response, StatusCode, Version,
 };
 use routerify::ext::RequestExt;
 use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Instant;
 
-/// Upgrades the http requests to websocket with the provided [config](./struct.WebSocketConfig.html).
+/// Upgrades the http requests to websocket with the provided config.
+///
+/// The config can either be an [`UpgradeConfig`](./struct.UpgradeConfig.html) or a plain [`WebSocketConfig`](./struct.WebSocketConfig.html).
 ///
 /// # Examples
 ///
@@ -54,49 +62,181 @@ use std::future::Future;
 /// #     }
 /// # }
 /// ```
-pub fn upgrade_ws_with_config<H, R, B, E>(
+pub fn upgrade_ws_with_config<H, R, B, E, C>(
     handler: H,
-    config: WebSocketConfig,
+    config: C,
 ) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
 where
     H: Fn(WebSocket) -> R + Copy + Send + Sync + 'static,
     R: Future<Output = ()> + Send + 'static,
     B: From<&'static str> + HttpBody + Send + 'static,
     E: std::error::Error + Send + 'static,
+    C: Into<UpgradeConfig>,
 {
-    return move |req: Request<hyper::Body>| {
-        let sec_key = extract_upgradable_key(&req);
-        let remote_addr = req.remote_addr();
-
-        if sec_key.is_none() {
-            return ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body("BAD REQUEST: The request is not websocket".into())
-                .unwrap());
+    upgrade_with(handler, config.into())
+}
+
+/// Builds the upgrade route handler, the handler is cloned for every new connection.
+pub(crate) fn upgrade_with<H, R, B, E>(
+    handler: H,
+    config: UpgradeConfig,
+) -> impl Fn(Request<hyper::Body>) -> Ready<Result<Response<B>, E>> + Send + Sync + 'static
+where
+    H: Fn(WebSocket) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = ()> + Send + 'static,
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    move |mut req: Request<hyper::Body>| {
+        let handshake = match validate_handshake(&req) {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                metrics::upgrade_rejected(err.reason());
+                return ok(err.response());
+            }
+        };
+
+        let mut admission = match admit(&req, &config) {
+            Ok(admission) => admission,
+            Err(resp) => return ok(resp),
+        };
+
+        let mut resp = handshake.response(admission.subprotocol.clone());
+        if let Some(ref hook) = config.response_hook {
+            (hook.0)(&req, resp.headers_mut());
         }
 
-        tokio::spawn(async move {
-            match hyper::upgrade::on(req).await {
+        let on_upgrade = hyper::upgrade::on(&mut req);
+        admission.adopt_extensions(req);
+
+        let span = admission.span.clone();
+        let config = config.clone();
+        let handler = handler.clone();
+        tokio::spawn(span.instrument(async move {
+            match on_upgrade.await {
                 Ok(upgraded) => {
-                    handler(WebSocket::from_raw_socket(upgraded, remote_addr, config).await).await;
+                    admission.span.handshake();
+                    let ws = WebSocket::from_raw_socket(upgraded, Role::Server, admission, &config).await;
+                    handler(ws).await;
+                }
+                Err(err) => {
+                    let err = crate::WebsocketError::Upgrade(err.into());
+                    metrics::error(&err);
+                    admission.span.error(&err);
+                    log::error!("{}", err)
                 }
-                Err(err) => log::error!("{}", crate::WebsocketError::Upgrade(err.into())),
             }
-        });
-
-        let resp = Response::builder()
-            .status(StatusCode::SWITCHING_PROTOCOLS)
-            .header(header::CONNECTION, encode_header(Connection::upgrade()))
-            .header(header::UPGRADE, encode_header(Upgrade::websocket()))
-            .header(
-                header::SEC_WEBSOCKET_ACCEPT,
-                encode_header(SecWebsocketAccept::from(sec_key.unwrap())),
-            )
-            .body("".into())
-            .unwrap();
+        }));
 
         ok(resp)
+    }
+}
+
+/// The connection details of an accepted upgrade request.
+pub(crate) struct Admission {
+    pub(crate) remote_addr: SocketAddr,
+    pub(crate) client_addr: SocketAddr,
+    pub(crate) subprotocol: Option<String>,
+    pub(crate) permit: Option<ConnectionPermit>,
+    pub(crate) span: ConnectionSpan,
+    pub(crate) extensions: Extensions,
+    pub(crate) expires_at: Option<Instant>,
+}
+
+impl Admission {
+    /// Moves the extensions of the request into the connection, the ones inserted by the guards take precedence.
+    pub(crate) fn adopt_extensions(&mut self, mut req: Request<hyper::Body>) {
+        let mut extensions = std::mem::take(req.extensions_mut());
+        extensions.extend(std::mem::take(&mut self.extensions));
+        self.extensions = extensions;
+    }
+}
+
+/// Runs the guards of a request, returning the extensions they inserted or the response to reject it with.
+pub(crate) fn run_guards<B>(req: &Request<hyper::Body>, config: &UpgradeConfig) -> Result<Extensions, Response<B>>
+where
+    B: From<&'static str>,
+{
+    let mut extensions = Extensions::new();
+    for guard in &config.guards {
+        if let Err(status) = (guard.0)(req, &mut extensions) {
+            return Err(Response::builder()
+                .status(status)
+                .body(status.canonical_reason().unwrap_or_default().into())
+                .unwrap());
+        }
+    }
+    Ok(extensions)
+}
+
+/// Negotiates the subprotocol, runs the guards, checks the session expiry and enforces the connection limits of a request,
+/// returning the response to reject it with.
+pub(crate) fn admit<B>(req: &Request<hyper::Body>, config: &UpgradeConfig) -> Result<Admission, Response<B>>
+where
+    B: From<&'static str>,
+{
+    let remote_addr = req.remote_addr();
+    let client_addr = match config.trusted_proxies {
+        Some(ref proxies) => proxies.resolve(remote_addr, req.headers()),
+        None => remote_addr,
+    };
+
+    let subprotocol = select_subprotocol(req, &config.subprotocols);
+    if subprotocol.is_none() && config.require_subprotocol {
+        metrics::upgrade_rejected("unsupported_subprotocol");
+        return Err(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("BAD REQUEST: None of the requested websocket subprotocols is supported".into())
+            .unwrap());
+    }
+
+    let extensions = match run_guards(req, config) {
+        Ok(extensions) => extensions,
+        Err(resp) => {
+            metrics::upgrade_rejected("guard");
+            return Err(resp);
+        }
+    };
+
+    let expires_at = config
+        .session
+        .as_ref()
+        .and_then(|session| (session.expires_at)(req, &extensions));
+    if expires_at.is_some_and(|expires_at| expires_at <= Instant::now()) {
+        metrics::upgrade_rejected("session_expired");
+        return Err(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body("UNAUTHORIZED: The session has expired".into())
+            .unwrap());
+    }
+
+    let permit = match config.limits {
+        Some(ref limits) => match limits.acquire(req, &extensions, client_addr.ip()) {
+            Ok(permit) => Some(permit),
+            Err(exceeded) => {
+                metrics::upgrade_rejected(exceeded.reason());
+                return Err(Response::builder()
+                    .status(exceeded.status())
+                    .header(header::RETRY_AFTER, limits.retry_after_secs())
+                    .body(exceeded.message().into())
+                    .unwrap());
+            }
+        },
+        None => None,
     };
+
+    metrics::upgrade_accepted();
+    let span = ConnectionSpan::new(client_addr, req.uri().path(), subprotocol.as_deref());
+
+    Ok(Admission {
+        remote_addr,
+        client_addr,
+        subprotocol,
+        permit,
+        span,
+        extensions,
+        expires_at,
+    })
 }
 
 /// Upgrades the http requests to websocket.
@@ -153,41 +293,457 @@ where
     B: From<&'static str> + HttpBody + Send + 'static,
     E: std::error::Error + Send + 'static,
 {
-    return upgrade_ws_with_config(handler, WebSocketConfig::default());
+    upgrade_ws_with_config(handler, WebSocketConfig::default())
 }
 
-fn extract_upgradable_key(req: &Request<hyper::Body>) -> Option<SecWebsocketKey> {
+/// The future of a route upgrading the websocket handshakes or serving them with a plain http handler.
+type UpgradeOr<B, E, Fut> = Either<Ready<Result<Response<B>, E>>, Fut>;
+
+/// Upgrades the websocket handshakes on a route and serves the other requests with a plain http handler, so the
+/// same path can serve both.
+///
+/// The requests asking for a websocket upgrade never reach the http handler: the invalid handshakes are rejected like
+/// with [`upgrade_ws`](./fn.upgrade_ws.html).
+///
+/// # Examples
+///
+/// ```no_run
+/// use hyper::{Body, Request, Response};
+/// use routerify::Router;
+/// use routerify_websocket::{upgrade_ws_or, WebSocket};
+/// use std::convert::Infallible;
+///
+/// async fn ws_handler(ws: WebSocket) {
+///     println!("New websocket connection: {}", ws.remote_addr());
+/// }
+///
+/// async fn status_handler(_req: Request<Body>) -> Result<Response<Body>, Infallible> {
+///     Ok(Response::new(Body::from(r#"{"status":"ok"}"#)))
+/// }
+///
+/// fn router() -> Router<Body, Infallible> {
+///     Router::builder()
+///         // Serve the websocket connections and a JSON status page at `/events`.
+///         .get("/events", upgrade_ws_or(ws_handler, status_handler))
+///         .build()
+///         .unwrap()
+/// }
+/// ```
+pub fn upgrade_ws_or<H, R, F, Fut, B, E>(
+    handler: H,
+    fallback: F,
+) -> impl Fn(Request<hyper::Body>) -> UpgradeOr<B, E, Fut> + Send + Sync + 'static
+where
+    H: Fn(WebSocket) -> R + Copy + Send + Sync + 'static,
+    R: Future<Output = ()> + Send + 'static,
+    F: Fn(Request<hyper::Body>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<B>, E>> + Send + 'static,
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    upgrade_ws_or_with_config(handler, fallback, WebSocketConfig::default())
+}
+
+/// Upgrades the websocket handshakes on a route with the provided config and serves the other requests with a
+/// plain http handler, see [`upgrade_ws_or`](./fn.upgrade_ws_or.html).
+pub fn upgrade_ws_or_with_config<H, R, F, Fut, B, E, C>(
+    handler: H,
+    fallback: F,
+    config: C,
+) -> impl Fn(Request<hyper::Body>) -> UpgradeOr<B, E, Fut> + Send + Sync + 'static
+where
+    H: Fn(WebSocket) -> R + Copy + Send + Sync + 'static,
+    R: Future<Output = ()> + Send + 'static,
+    F: Fn(Request<hyper::Body>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<B>, E>> + Send + 'static,
+    B: From<&'static str> + HttpBody + Send + 'static,
+    E: std::error::Error + Send + 'static,
+    C: Into<UpgradeConfig>,
+{
+    let upgrade = upgrade_with(handler, config.into());
+
+    move |req: Request<hyper::Body>| {
+        if is_upgrade_request(&req) {
+            Either::Left(upgrade(req))
+        } else {
+            Either::Right(fallback(req))
+        }
+    }
+}
+
+/// The reasons to reject an opening handshake, see [RFC 6455 §4.2.1](https://tools.ietf.org/html/rfc6455#section-4.2.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HandshakeError {
+    Method,
+    HttpVersion,
+    MissingHost,
+    NotUpgrade,
+    NotWebSocket,
+    MissingVersion,
+    UnsupportedVersion,
+    InvalidKey,
+}
+
+impl HandshakeError {
+    /// The reason reported to the metrics.
+    pub(crate) fn reason(self) -> &'static str {
+        match self {
+            HandshakeError::Method => "invalid_method",
+            HandshakeError::HttpVersion => "invalid_http_version",
+            HandshakeError::MissingHost => "missing_host",
+            HandshakeError::NotUpgrade | HandshakeError::NotWebSocket => "not_websocket",
+            HandshakeError::MissingVersion | HandshakeError::UnsupportedVersion => "unsupported_version",
+            HandshakeError::InvalidKey => "invalid_key",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            HandshakeError::Method => "METHOD NOT ALLOWED: The websocket handshake must be a GET request",
+            HandshakeError::HttpVersion => "BAD REQUEST: The websocket handshake requires HTTP/1.1 or higher",
+            HandshakeError::MissingHost => "BAD REQUEST: The websocket handshake is missing the Host header",
+            HandshakeError::NotUpgrade => "BAD REQUEST: The Connection header doesn't contain the upgrade token",
+            HandshakeError::NotWebSocket => "BAD REQUEST: The request is not websocket",
+            HandshakeError::MissingVersion => {
+                "UPGRADE REQUIRED: The websocket handshake is missing the Sec-WebSocket-Version header"
+            }
+            HandshakeError::UnsupportedVersion => "UPGRADE REQUIRED: Only the websocket version 13 is supported",
+            HandshakeError::InvalidKey => "BAD REQUEST: The Sec-WebSocket-Key header is missing or invalid",
+        }
+    }
+
+    /// The response rejecting the handshake.
+    pub(crate) fn response<B: From<&'static str>>(self) -> Response<B> {
+        let builder = match self {
+            HandshakeError::Method => Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, "GET"),
+            HandshakeError::MissingVersion | HandshakeError::UnsupportedVersion => Response::builder()
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .header(header::SEC_WEBSOCKET_VERSION, "13"),
+            _ => Response::builder().status(StatusCode::BAD_REQUEST),
+        };
+        builder.body(self.message().into()).unwrap()
+    }
+}
+
+/// A valid opening handshake.
+pub(crate) enum Handshake {
+    /// An HTTP/1.1 `Upgrade` request, with its key.
+    Upgrade(SecWebsocketKey),
+    /// An HTTP/2 extended `CONNECT` request, see [RFC 8441](https://tools.ietf.org/html/rfc8441).
+    #[cfg(feature = "http2")]
+    ExtendedConnect,
+}
+
+impl Handshake {
+    /// The response accepting the handshake.
+    pub(crate) fn response<B: From<&'static str>>(self, subprotocol: Option<String>) -> Response<B> {
+        let mut builder = match self {
+            Handshake::Upgrade(sec_key) => Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(header::CONNECTION, encode_header(Connection::upgrade()))
+                .header(header::UPGRADE, encode_header(Upgrade::websocket()))
+                .header(
+                    header::SEC_WEBSOCKET_ACCEPT,
+                    encode_header(SecWebsocketAccept::from(sec_key)),
+                ),
+            #[cfg(feature = "http2")]
+            Handshake::ExtendedConnect => Response::builder().status(StatusCode::OK),
+        };
+        if let Some(subprotocol) = subprotocol {
+            builder = builder.header(header::SEC_WEBSOCKET_PROTOCOL, subprotocol);
+        }
+        builder.body("".into()).unwrap()
+    }
+}
+
+/// Returns true if the request asks for a websocket upgrade, valid or not.
+pub(crate) fn is_upgrade_request<T>(req: &Request<T>) -> bool {
+    #[cfg(feature = "http2")]
+    if is_extended_connect(req) {
+        return true;
+    }
+
+    has_token(req, header::UPGRADE, "websocket")
+}
+
+/// Returns true if the request is an HTTP/2 extended `CONNECT` for the websocket protocol.
+#[cfg(feature = "http2")]
+fn is_extended_connect<T>(req: &Request<T>) -> bool {
+    req.method() == Method::CONNECT
+        && req
+            .extensions()
+            .get::<hyper::ext::Protocol>()
+            .is_some_and(|protocol| protocol.as_str().eq_ignore_ascii_case("websocket"))
+}
+
+/// Validates the opening handshake of a websocket request, as specified by
+/// [RFC 6455 §4.2.1](https://tools.ietf.org/html/rfc6455#section-4.2.1), or by
+/// [RFC 8441 §5](https://tools.ietf.org/html/rfc8441#section-5) over HTTP/2.
+pub(crate) fn validate_handshake<T>(req: &Request<T>) -> Result<Handshake, HandshakeError> {
+    #[cfg(feature = "http2")]
+    if is_extended_connect(req) {
+        return check_version(req).map(|_| Handshake::ExtendedConnect);
+    }
+
+    if req.method() != Method::GET {
+        return Err(HandshakeError::Method);
+    }
+    if req.version() < Version::HTTP_11 {
+        return Err(HandshakeError::HttpVersion);
+    }
+
     let hdrs = req.headers();
+    if !hdrs.contains_key(header::HOST) {
+        return Err(HandshakeError::MissingHost);
+    }
+    if !is_upgrade_request(req) {
+        return Err(HandshakeError::NotWebSocket);
+    }
+    if !has_token(req, header::CONNECTION, "upgrade") {
+        return Err(HandshakeError::NotUpgrade);
+    }
+    check_version(req)?;
+
+    // The key is a base64 encoded 16 bytes nonce.
+    hdrs.get(header::SEC_WEBSOCKET_KEY)
+        .filter(|val| {
+            let key = val.as_bytes();
+            key.len() == 24
+                && key.ends_with(b"==")
+                && key[..22]
+                    .iter()
+                    .all(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+        })
+        .and_then(decode_header::<SecWebsocketKey>)
+        .map(Handshake::Upgrade)
+        .ok_or(HandshakeError::InvalidKey)
+}
+
+fn check_version<T>(req: &Request<T>) -> Result<(), HandshakeError> {
+    match req
+        .headers()
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .map(|val| val.to_str().map(str::trim))
+    {
+        Some(Ok("13")) => Ok(()),
+        Some(_) => Err(HandshakeError::UnsupportedVersion),
+        None => Err(HandshakeError::MissingVersion),
+    }
+}
 
-    hdrs.get(header::CONNECTION)
-        .and_then(|val| decode_header::<Connection>(val))
-        .and_then(|conn| some(conn.contains("upgrade")))
-        .and_then(|_| hdrs.get(header::UPGRADE))
-        .and_then(|val| val.to_str().ok())
-        .and_then(|val| some(val == "websocket"))
-        .and_then(|_| hdrs.get(header::SEC_WEBSOCKET_VERSION))
-        .and_then(|val| val.to_str().ok())
-        .and_then(|val| some(val == "13"))
-        .and_then(|_| hdrs.get(header::SEC_WEBSOCKET_KEY))
-        .and_then(|val| decode_header::<SecWebsocketKey>(val))
+/// Returns true if one of the comma separated values of the header is the token, compared case-insensitively.
+fn has_token<T>(req: &Request<T>, name: header::HeaderName, token: &str) -> bool {
+    req.headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .any(|val| val.trim().eq_ignore_ascii_case(token))
+}
+
+/// Selects the most preferred supported subprotocol among the ones offered by the client.
+fn select_subprotocol(req: &Request<hyper::Body>, supported: &[String]) -> Option<String> {
+    let offered = req
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+
+    supported
+        .iter()
+        .find(|protocol| offered.contains(&protocol.as_str()))
+        .cloned()
 }
 
 fn decode_header<T: Header>(val: &HeaderValue) -> Option<T> {
     let values = [val];
-    let mut iter = (&values).into_iter().copied();
+    let mut iter = values.iter().copied();
     T::decode(&mut iter).ok()
 }
 
 fn encode_header<T: Header>(h: T) -> HeaderValue {
     let mut val = Vec::with_capacity(1);
     h.encode(&mut val);
-    val.into_iter().nth(0).unwrap()
+    val.into_iter().next().unwrap()
 }
 
-fn some(cond: bool) -> Option<()> {
-    if cond {
-        Some(())
-    } else {
-        None
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn request() -> hyper::http::request::Builder {
+        Request::get("/ws")
+            .header(header::HOST, "example.com")
+            .header(header::UPGRADE, "websocket")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    fn rejected(req: Request<()>) -> Option<HandshakeError> {
+        validate_handshake(&req).err()
+    }
+
+    #[test]
+    fn accepts_valid_handshake() {
+        let req = request().body(()).unwrap();
+        let res = validate_handshake(&req).unwrap().response::<&str>(None);
+
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(res.headers()[header::UPGRADE], "websocket");
+        assert_eq!(res.headers()[header::CONNECTION], "upgrade");
+        assert_eq!(
+            res.headers()[header::SEC_WEBSOCKET_ACCEPT],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert!(!res.headers().contains_key(header::SEC_WEBSOCKET_PROTOCOL));
+    }
+
+    #[test]
+    fn accepts_token_lists_in_any_case() {
+        let req = Request::get("/ws")
+            .header(header::HOST, "example.com")
+            .header(header::UPGRADE, "h2c, WebSocket")
+            .header(header::CONNECTION, "keep-alive")
+            .header(header::CONNECTION, "HTTP2-Settings, upgrade")
+            .header(header::SEC_WEBSOCKET_VERSION, " 13 ")
+            .header(header::SEC_WEBSOCKET_KEY, "AAAAAAAAAAAAAAAAAAAAAA==")
+            .body(())
+            .unwrap();
+
+        assert!(is_upgrade_request(&req));
+        assert_eq!(rejected(req), None);
+    }
+
+    #[test]
+    fn rejects_invalid_method_and_version() {
+        let req = request().method(Method::POST).body(()).unwrap();
+        assert_eq!(rejected(req), Some(HandshakeError::Method));
+
+        let req = request().version(Version::HTTP_10).body(()).unwrap();
+        assert_eq!(rejected(req), Some(HandshakeError::HttpVersion));
+    }
+
+    #[test]
+    fn rejects_missing_headers() {
+        let without = |name: header::HeaderName| {
+            let mut req = request().body(()).unwrap();
+            req.headers_mut().remove(name);
+            rejected(req)
+        };
+
+        assert_eq!(without(header::HOST), Some(HandshakeError::MissingHost));
+        assert_eq!(without(header::UPGRADE), Some(HandshakeError::NotWebSocket));
+        assert_eq!(without(header::CONNECTION), Some(HandshakeError::NotUpgrade));
+        assert_eq!(
+            without(header::SEC_WEBSOCKET_VERSION),
+            Some(HandshakeError::MissingVersion)
+        );
+        assert_eq!(without(header::SEC_WEBSOCKET_KEY), Some(HandshakeError::InvalidKey));
+    }
+
+    #[test]
+    fn rejects_invalid_header_values() {
+        let with = |name: header::HeaderName, val: &'static str| {
+            let mut req = request().body(()).unwrap();
+            req.headers_mut().insert(name, HeaderValue::from_static(val));
+            rejected(req)
+        };
+
+        assert_eq!(with(header::UPGRADE, "h2c"), Some(HandshakeError::NotWebSocket));
+        assert_eq!(with(header::CONNECTION, "keep-alive"), Some(HandshakeError::NotUpgrade));
+        assert_eq!(
+            with(header::SEC_WEBSOCKET_VERSION, "8"),
+            Some(HandshakeError::UnsupportedVersion)
+        );
+        assert_eq!(
+            with(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ"),
+            Some(HandshakeError::InvalidKey)
+        );
+        assert_eq!(
+            with(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQo="),
+            Some(HandshakeError::InvalidKey)
+        );
+        assert_eq!(
+            with(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25j-Q=="),
+            Some(HandshakeError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn rejection_responses() {
+        let res = HandshakeError::Method.response::<&str>();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[header::ALLOW], "GET");
+
+        for err in [HandshakeError::MissingVersion, HandshakeError::UnsupportedVersion] {
+            let res = err.response::<&str>();
+            assert_eq!(res.status(), StatusCode::UPGRADE_REQUIRED);
+            assert_eq!(res.headers()[header::SEC_WEBSOCKET_VERSION], "13");
+        }
+
+        let res = HandshakeError::InvalidKey.response::<&str>();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn selects_most_preferred_subprotocol() {
+        let req = request()
+            .header(header::SEC_WEBSOCKET_PROTOCOL, "mqtt, graphql-ws")
+            .header(header::SEC_WEBSOCKET_PROTOCOL, "stomp")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let supported = |protocols: &[&str]| protocols.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            select_subprotocol(&req, &supported(&["stomp", "graphql-ws"])),
+            Some("stomp".to_owned())
+        );
+        assert_eq!(
+            select_subprotocol(&req, &supported(&["graphql-transport-ws", "graphql-ws"])),
+            Some("graphql-ws".to_owned())
+        );
+        assert_eq!(select_subprotocol(&req, &supported(&["wamp"])), None);
+    }
+
+    #[cfg(feature = "http2")]
+    #[test]
+    fn accepts_extended_connect() {
+        let mut req = Request::connect("/ws")
+            .version(Version::HTTP_2)
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(hyper::ext::Protocol::from_static("websocket"));
+
+        assert!(is_upgrade_request(&req));
+        let res = validate_handshake(&req).unwrap().response::<&str>(None);
+        assert_eq!(res.status(), StatusCode::OK);
+
+        req.headers_mut().remove(header::SEC_WEBSOCKET_VERSION);
+        assert_eq!(rejected(req), Some(HandshakeError::MissingVersion));
+    }
+
+    #[test]
+    fn rejects_invalid_handshakes_instead_of_serving_them() {
+        let route = upgrade_ws_or(
+            |_ws| async {},
+            |_req| async { Ok::<_, std::convert::Infallible>(Response::new(hyper::Body::from("http"))) },
+        );
+        let serve = |req: Request<hyper::Body>| route(req).now_or_never().unwrap().unwrap();
+
+        let mut req = request().body(hyper::Body::empty()).unwrap();
+        req.headers_mut().remove(header::SEC_WEBSOCKET_KEY);
+        assert_eq!(serve(req).status(), StatusCode::BAD_REQUEST);
+
+        let req = Request::get("/ws").body(hyper::Body::empty()).unwrap();
+        assert_eq!(serve(req).status(), StatusCode::OK);
     }
 }
@@ -1,107 +1,969 @@
-use crate::{CloseCode, Message, WebSocketConfig};
-use futures::{ready, FutureExt, Sink, Stream};
+use crate::fragment::{Fragment, FrameFilter, Incoming, MessageStream};
+use crate::limit::ConnectionPermit;
+use crate::message::close_reason;
+use crate::metrics::{self, ConnectionGauge, Direction};
+use crate::middleware::Middleware;
+use crate::rate_limit::{RateLimiter, Verdict};
+use crate::session::{expired_frame, RefreshFn};
+use crate::trace::ConnectionSpan;
+use crate::upgrade::Admission;
+use crate::{
+    CloseCode, ErrorKind, Message, MiddlewareContext, MiddlewareFlow, RateLimit, UpgradeConfig, WebSocketReader,
+    WebSocketWriter,
+};
+use futures::future::poll_fn;
+use futures::{pin_mut, ready, Sink, SinkExt, Stream, StreamExt};
+use hyper::{http::Extensions, upgrade::Upgraded};
 use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::fmt;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
 use tokio_tungstenite::{
-    tungstenite::protocol::{CloseFrame, Role},
+    tungstenite::{
+        error::CapacityError,
+        protocol::{
+            self,
+            frame::{
+                coding::{Data, OpCode},
+                Frame,
+            },
+            CloseFrame, Role,
+        },
+        Bytes, Error, Utf8Bytes,
+    },
     WebSocketStream,
 };
 
+/// The size of the chunks read by [`WebSocket::send_reader`](./struct.WebSocket.html#method.send_reader).
+const SEND_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The transport carrying the messages of a connection.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Transport<S> {
+    WebSocket(WebSocketStream<FrameFilter<S>>),
+    #[cfg(feature = "fallback")]
+    Fallback(crate::fallback::FallbackStream),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Transport<S> {
+    fn filter_mut(&mut self) -> Option<&mut FrameFilter<S>> {
+        match self {
+            Transport::WebSocket(inner) => Some(inner.get_mut()),
+            #[cfg(feature = "fallback")]
+            Transport::Fallback(_) => None,
+        }
+    }
+}
+
+/// An item read from the transport, in the order it arrived.
+pub(crate) enum Item {
+    Message(protocol::Message),
+    Fragment(Fragment),
+}
+
+/// A message received by the stream or the start of a streamed one.
+enum Received {
+    Message(protocol::Message),
+    Stream { text: bool },
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for Transport<S> {
+    type Item = Result<protocol::Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            Transport::WebSocket(inner) => Pin::new(inner).poll_next(cx),
+            #[cfg(feature = "fallback")]
+            Transport::Fallback(inner) => Pin::new(inner).poll_next(cx),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<protocol::Message> for Transport<S> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Transport::WebSocket(inner) => Pin::new(inner).poll_ready(cx),
+            #[cfg(feature = "fallback")]
+            Transport::Fallback(inner) => Pin::new(inner).poll_ready(cx),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: protocol::Message) -> Result<(), Self::Error> {
+        match self.get_mut() {
+            Transport::WebSocket(inner) => Pin::new(inner).start_send(item),
+            #[cfg(feature = "fallback")]
+            Transport::Fallback(inner) => Pin::new(inner).start_send(item),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Transport::WebSocket(inner) => Pin::new(inner).poll_flush(cx),
+            #[cfg(feature = "fallback")]
+            Transport::Fallback(inner) => Pin::new(inner).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            Transport::WebSocket(inner) => Pin::new(inner).poll_close(cx),
+            #[cfg(feature = "fallback")]
+            Transport::Fallback(inner) => Pin::new(inner).poll_close(cx),
+        }
+    }
+}
+
 /// The WebSocket input-output stream.
 ///
 /// It implements the [`Stream`](https://docs.rs/futures/0.3.5/futures/stream/trait.Stream.html) and [`Sink`](https://docs.rs/futures/0.3.5/futures/sink/trait.Sink.html)
 /// traits, so the socket is just a stream of messages coming in and going out.
-pub struct WebSocket {
-    inner: WebSocketStream<hyper::upgrade::Upgraded>,
+pub struct WebSocket<S = Upgraded> {
+    inner: Transport<S>,
     remote_addr: SocketAddr,
+    client_addr: SocketAddr,
+    subprotocol: Option<String>,
+    extensions: Extensions,
+    _permit: Option<ConnectionPermit>,
+    rate_limiter: Option<RateLimiter>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    expiry: Option<Pin<Box<Sleep>>>,
+    expired_frame: CloseFrame,
+    refresh: Option<Arc<RefreshFn>>,
+    pending_close: Option<CloseFrame>,
+    flushing_close: bool,
+    max_message_size: Option<usize>,
+    close_on_error: bool,
+    middlewares: Vec<Middleware>,
+    backlog: VecDeque<Item>,
+    deferred: VecDeque<protocol::Message>,
+    read_error: Option<Error>,
+    assembling: Option<(bool, Vec<u8>)>,
+    discarding: bool,
+    _gauge: ConnectionGauge,
+    span: ConnectionSpan,
 }
 
-impl WebSocket {
-    pub(crate) async fn from_raw_socket(
-        upgraded: hyper::upgrade::Upgraded,
-        remote_addr: SocketAddr,
-        config: WebSocketConfig,
-    ) -> Self {
-        WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config))
-            .map(|inner| WebSocket { inner, remote_addr })
-            .await
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    /// Creates a `WebSocket` over an already established connection, e.g. a `tokio::io::duplex` stream in the tests,
+    /// a Unix socket or a TLS stream terminated by the application.
+    ///
+    /// The handshake must have been completed on the connection. The [connection limits](./struct.ConnectionLimits.html)
+    /// and the [trusted proxies](./struct.TrustedProxies.html) of the config don't apply, as they are enforced during the
+    /// upgrade, and the fragmented messages are only [streamed](./struct.UpgradeConfig.html#method.stream_fragmented) on
+    /// the server side.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use futures::{SinkExt, StreamExt};
+    /// use routerify_websocket::{Message, Role, UpgradeConfig, WebSocket};
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let (client, server) = tokio::io::duplex(1024);
+    /// let addr = "127.0.0.1:3001".parse().unwrap();
+    ///
+    /// let mut client = WebSocket::from_stream(client, Role::Client, UpgradeConfig::new(), addr).await;
+    /// let mut server = WebSocket::from_stream(server, Role::Server, UpgradeConfig::new(), addr).await;
+    ///
+    /// client.send(Message::text("Hello world")).await.unwrap();
+    /// assert_eq!(server.next().await.unwrap().unwrap(), Message::text("Hello world"));
+    /// # }
+    /// ```
+    pub async fn from_stream<C: Into<UpgradeConfig>>(io: S, role: Role, config: C, remote_addr: SocketAddr) -> Self {
+        let config = config.into();
+        let admission = Admission {
+            remote_addr,
+            client_addr: remote_addr,
+            subprotocol: None,
+            permit: None,
+            span: ConnectionSpan::new(remote_addr, "", None),
+            extensions: Extensions::new(),
+            expires_at: None,
+        };
+        WebSocket::from_raw_socket(io, role, admission, &config).await
+    }
+
+    pub(crate) async fn from_raw_socket(io: S, role: Role, admission: Admission, config: &UpgradeConfig) -> Self {
+        let filter = FrameFilter::new(
+            io,
+            config.stream_fragmented && role == Role::Server,
+            config.ws_config.max_frame_size,
+        );
+        let inner = WebSocketStream::from_raw_socket(filter, role, Some(config.ws_config)).await;
+        WebSocket::from_transport(Transport::WebSocket(inner), admission, config)
+    }
+
+    pub(crate) fn from_transport(inner: Transport<S>, admission: Admission, config: &UpgradeConfig) -> Self {
+        WebSocket {
+            inner,
+            remote_addr: admission.remote_addr,
+            client_addr: admission.client_addr,
+            subprotocol: admission.subprotocol,
+            extensions: admission.extensions,
+            _permit: admission.permit,
+            rate_limiter: config.rate_limit.map(RateLimiter::new),
+            read_delay: None,
+            expiry: admission
+                .expires_at
+                .map(|at| Box::pin(tokio::time::sleep_until(at.into()))),
+            expired_frame: expired_frame(config.session.as_ref()),
+            refresh: config.session.as_ref().and_then(|session| session.refresh.clone()),
+            pending_close: None,
+            flushing_close: false,
+            max_message_size: config.ws_config.max_message_size,
+            close_on_error: config.close_on_error,
+            middlewares: config.middlewares.clone(),
+            backlog: VecDeque::new(),
+            deferred: VecDeque::new(),
+            read_error: None,
+            assembling: None,
+            discarding: false,
+            _gauge: ConnectionGauge::new(),
+            span: admission.span,
+        }
     }
 
     /// Get the peer's remote address.
+    ///
+    /// It is the address of the TCP peer, the same as [`peer_addr`](#method.peer_addr).
     pub fn remote_addr(&self) -> SocketAddr {
         self.remote_addr
     }
 
+    /// Get the address of the TCP peer, which is the nearest proxy if the connection is proxied.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Get the client address resolved from the forwarding headers sent by the
+    /// [trusted proxies](./struct.TrustedProxies.html).
+    ///
+    /// It is the same as [`peer_addr`](#method.peer_addr) if the peer is not a trusted proxy or no trusted proxies are
+    /// configured. The port is `0` if the forwarding headers don't contain it.
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    /// Get the subprotocol selected during the handshake, if any.
+    ///
+    /// See [`UpgradeConfig::subprotocols`](./struct.UpgradeConfig.html#method.subprotocols).
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+
+    /// Get the extensions of the connection, which hold the extensions of the upgrade request and the data inserted by
+    /// the [guards](./struct.UpgradeConfig.html#method.guard).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use routerify_websocket::WebSocket;
+    ///
+    /// struct User(String);
+    ///
+    /// async fn ws_handler(ws: WebSocket) {
+    ///     if let Some(User(name)) = ws.extensions().get::<User>() {
+    ///         println!("{} is connected", name);
+    ///     }
+    /// }
+    /// ```
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Get a mutable reference to the extensions of the connection, to keep per-connection data.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Sets or removes the [rate limit](./struct.RateLimit.html) applied to the incoming messages of this connection.
+    ///
+    /// It overrides the rate limit set on the [`UpgradeConfig`](./struct.UpgradeConfig.html) and starts with a full budget.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limiter = rate_limit.map(RateLimiter::new);
+        self.read_delay = None;
+    }
+
+    /// Get the instant the [session](./struct.SessionExpiry.html) of the connection expires at, if any.
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.expiry.as_ref().map(|expiry| expiry.deadline().into_std())
+    }
+
+    /// Sets or removes the instant the session of the connection expires at, e.g. once the client re-authenticated
+    /// through another channel.
+    ///
+    /// The connection is closed once it expires, see [`SessionExpiry`](./struct.SessionExpiry.html).
+    pub fn set_expires_at(&mut self, expires_at: Option<Instant>) {
+        match (expires_at, self.expiry.as_mut()) {
+            (Some(at), Some(expiry)) => expiry.as_mut().reset(at.into()),
+            (at, _) => self.expiry = at.map(|at| Box::pin(tokio::time::sleep_until(at.into()))),
+        }
+    }
+
+    /// Extends the session if the message is a re-authentication message, returning true if it is consumed.
+    fn refresh_session(&mut self, msg: &Message) -> bool {
+        let refresh = match self.refresh {
+            Some(ref refresh) if msg.is_text() || msg.is_binary() => refresh.clone(),
+            _ => return false,
+        };
+
+        let mut ctx = MiddlewareContext {
+            remote_addr: self.remote_addr,
+            client_addr: self.client_addr,
+            subprotocol: self.subprotocol.as_deref(),
+            extensions: &mut self.extensions,
+        };
+        match refresh(&mut ctx, msg) {
+            Some(expires_at) => {
+                self.set_expires_at(Some(expires_at));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reports a message going through the connection to the metrics and the tracing span.
+    fn observe(&self, direction: Direction, msg: &protocol::Message) {
+        metrics::message(direction, msg);
+        self.span.message(direction, msg);
+    }
+
+    /// Runs a message through the middlewares, in the order they were added for the incoming messages and in the
+    /// reverse order for the outgoing ones.
+    fn run_middlewares(&mut self, direction: Direction, mut msg: Message) -> MiddlewareFlow {
+        let mut ctx = MiddlewareContext {
+            remote_addr: self.remote_addr,
+            client_addr: self.client_addr,
+            subprotocol: self.subprotocol.as_deref(),
+            extensions: &mut self.extensions,
+        };
+
+        let len = self.middlewares.len();
+        for i in 0..len {
+            let flow = match direction {
+                Direction::In => self.middlewares[i].0.inbound(&mut ctx, msg),
+                Direction::Out => self.middlewares[len - 1 - i].0.outbound(&mut ctx, msg),
+            };
+            match flow {
+                MiddlewareFlow::Continue(next) => msg = next,
+                flow => return flow,
+            }
+        }
+        MiddlewareFlow::Continue(msg)
+    }
+
+    pub(crate) fn span(&self) -> &ConnectionSpan {
+        &self.span
+    }
+
+    /// Reports an error to the metrics and the tracing span.
+    pub(crate) fn tracked(&self, err: crate::WebsocketError) -> crate::WebsocketError {
+        metrics::error(&err);
+        self.span.error(&err);
+        err
+    }
+
+    /// Reports an error while receiving a message, queueing the matching close frame for the peer if enabled.
+    pub(crate) fn receive_failed(&mut self, cx: &mut Context, err: Error) -> crate::WebsocketError {
+        let err = self.tracked(crate::WebsocketError::MessageReceive(err.into()));
+
+        let reason = match err.kind() {
+            ErrorKind::Protocol => "Protocol error",
+            ErrorKind::Utf8 => "Invalid UTF-8 text",
+            ErrorKind::Capacity => "Message too large",
+            _ => return err,
+        };
+        if let (true, None, Some(code)) = (self.close_on_error, &self.pending_close, err.close_code()) {
+            self.pending_close = Some(CloseFrame {
+                code,
+                reason: Utf8Bytes::from_static(reason),
+            });
+            // The frame stays queued if it can't be sent right away, and goes out with the next read.
+            let _ = self.poll_pending_close(cx);
+        }
+        err
+    }
+
+    /// Queues a close frame to be sent with the next read or by [`poll_pending_close`](#method.poll_pending_close),
+    /// unless one is already queued.
+    pub(crate) fn queue_close(&mut self, frame: CloseFrame) {
+        if self.pending_close.is_none() {
+            self.pending_close = Some(frame);
+        }
+    }
+
+    /// Closes the connection if its session expired, returning the error to report.
+    ///
+    /// It is polled whenever the connection is read or written, so the expiry wakes the task using it.
+    pub(crate) fn poll_expiry(&mut self, cx: &mut Context) -> Option<crate::WebsocketError> {
+        if self.expiry.as_mut()?.as_mut().poll(cx).is_pending() {
+            return None;
+        }
+
+        self.expiry = None;
+        let frame = self.expired_frame.clone();
+        let err = crate::WebsocketError::SessionExpired(frame.code);
+        Some(self.close_for(cx, frame, err))
+    }
+
+    /// Closes the connection with the frame because of the error, which is returned unless sending the frame fails.
+    fn close_for(&mut self, cx: &mut Context, frame: CloseFrame, err: crate::WebsocketError) -> crate::WebsocketError {
+        self.pending_close = Some(frame);
+        match self.poll_pending_close(cx) {
+            Poll::Ready(Err(err)) => err,
+            _ => self.tracked(err),
+        }
+    }
+
+    /// Drives the close frame queued by the stream, if any, out to the peer.
+    pub(crate) fn poll_pending_close(&mut self, cx: &mut Context) -> Poll<Result<(), crate::WebsocketError>> {
+        if let Some(frame) = self.pending_close.take() {
+            match Pin::new(&mut self.inner).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => {
+                    return Poll::Ready(Err(self.tracked(crate::WebsocketError::WebSocketClose(err.into()))))
+                }
+                Poll::Pending => {
+                    self.pending_close = Some(frame);
+                    return Poll::Pending;
+                }
+            }
+
+            let msg = protocol::Message::Close(Some(frame));
+            self.observe(Direction::Out, &msg);
+            if let Err(err) = Pin::new(&mut self.inner).start_send(msg) {
+                return Poll::Ready(Err(self.tracked(crate::WebsocketError::WebSocketClose(err.into()))));
+            }
+            self.flushing_close = true;
+        }
+
+        if self.flushing_close {
+            ready!(Pin::new(&mut self.inner).poll_flush(cx))
+                .map_err(|err| self.tracked(crate::WebsocketError::WebSocketClose(err.into())))?;
+            self.flushing_close = false;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Receives the next message, streaming the fragmented messages as their frames arrive.
+    ///
+    /// The fragmented messages are returned as an [`Incoming::Stream`](./enum.Incoming.html#variant.Stream) if
+    /// [`UpgradeConfig::stream_fragmented`](./struct.UpgradeConfig.html#method.stream_fragmented) is enabled, so
+    /// they are not bounded by the `max_message_size` of the [`WebSocketConfig`](./struct.WebSocketConfig.html).
+    /// Every other message is returned as a whole, like the [`Stream`](https://docs.rs/futures/0.3.5/futures/stream/trait.Stream.html)
+    /// implementation does.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use routerify_websocket::{Incoming, WebSocket};
+    ///
+    /// async fn ws_handler(mut ws: WebSocket) {
+    ///     while let Some(Ok(incoming)) = ws.next_incoming().await {
+    ///         match incoming {
+    ///             Incoming::Message(msg) => println!("{:?}", msg),
+    ///             Incoming::Stream(mut stream) => {
+    ///                 let mut len = 0;
+    ///                 while let Some(Ok(chunk)) = stream.next().await {
+    ///                     len += chunk.len();
+    ///                 }
+    ///                 println!("Streamed a message of {} bytes", len);
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn next_incoming(&mut self) -> Option<crate::Result<Incoming<'_, S>>> {
+        match poll_fn(|cx| self.poll_receive(cx, true)).await? {
+            Ok(Received::Message(inner)) => Some(Ok(Incoming::Message(Message { inner }))),
+            Ok(Received::Stream { text }) => {
+                metrics::fragment(Direction::In, text, 0, true);
+                Some(Ok(Incoming::Stream(MessageStream::new(self, text))))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Sends a `Binary` message from a stream of chunks, one frame per chunk, without buffering the whole message.
+    ///
+    /// The peer receives a single message once the stream ends. If the stream fails midway, the message can't be
+    /// completed and the connection should be closed.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use routerify_websocket::WebSocket;
+    ///
+    /// async fn ws_handler(mut ws: WebSocket) {
+    ///     let chunks = vec![Ok::<_, std::io::Error>(vec![1, 2]), Ok(vec![3, 4])];
+    ///     ws.send_binary_stream(futures::stream::iter(chunks)).await.unwrap();
+    /// }
+    /// ```
+    pub async fn send_binary_stream<St, B, E>(&mut self, stream: St) -> crate::Result<()>
+    where
+        St: Stream<Item = Result<B, E>>,
+        B: Into<Bytes>,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let chunks = stream.map(|chunk| chunk.map(Into::into).map_err(Into::into));
+        self.send_fragments(false, chunks).await
+    }
+
+    /// Sends a `Text` message from a stream of strings, one frame per string, without buffering the whole message.
+    ///
+    /// The peer receives a single message once the stream ends. If the stream fails midway, the message can't be
+    /// completed and the connection should be closed.
+    pub async fn send_text_stream<St, T, E>(&mut self, stream: St) -> crate::Result<()>
+    where
+        St: Stream<Item = Result<T, E>>,
+        T: Into<String>,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let chunks = stream.map(|chunk| chunk.map(|text| Bytes::from(text.into())).map_err(Into::into));
+        self.send_fragments(true, chunks).await
+    }
+
+    /// Sends a `Binary` message with the content of the reader, without buffering the whole message.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use routerify_websocket::WebSocket;
+    ///
+    /// async fn ws_handler(mut ws: WebSocket) {
+    ///     let file = tokio::fs::File::open("video.mp4").await.unwrap();
+    ///     ws.send_reader(file).await.unwrap();
+    /// }
+    /// ```
+    pub async fn send_reader<R: AsyncRead + Unpin>(&mut self, reader: R) -> crate::Result<()> {
+        let chunks = futures::stream::unfold((reader, vec![0; SEND_CHUNK_SIZE]), |(mut reader, mut buf)| async move {
+            let mut read_buf = ReadBuf::new(&mut buf);
+            match poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut read_buf)).await {
+                Ok(()) if read_buf.filled().is_empty() => None,
+                Ok(()) => {
+                    let chunk = Bytes::copy_from_slice(read_buf.filled());
+                    Some((Ok(chunk), (reader, buf)))
+                }
+                Err(err) => Some((Err(err.into()), (reader, buf))),
+            }
+        });
+        self.send_fragments(false, chunks).await
+    }
+
+    async fn send_fragments<St>(&mut self, text: bool, chunks: St) -> crate::Result<()>
+    where
+        St: Stream<Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>>,
+    {
+        pin_mut!(chunks);
+
+        if self.inner.filter_mut().is_none() {
+            // The fallback transport only carries whole messages.
+            let mut data = Vec::new();
+            while let Some(chunk) = chunks.next().await {
+                data.extend_from_slice(&chunk.map_err(|err| self.tracked(crate::WebsocketError::MessageSend(err)))?);
+            }
+            let msg = if text {
+                Message::text(
+                    String::from_utf8(data)
+                        .map_err(|err| self.tracked(crate::WebsocketError::MessageSend(err.into())))?,
+                )
+            } else {
+                Message::binary(data)
+            };
+            return self.send(msg).await;
+        }
+
+        // One chunk is held back, so the last frame is known when the stream ends.
+        let mut held: Option<Bytes> = None;
+        let mut first = true;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(|err| self.tracked(crate::WebsocketError::MessageSend(err)))?;
+            if chunk.is_empty() {
+                continue;
+            }
+            if let Some(prev) = held.replace(chunk) {
+                self.send_fragment(text, prev, first, false).await?;
+                first = false;
+            }
+        }
+        self.send_fragment(text, held.unwrap_or_default(), first, true).await?;
+
+        self.inner
+            .flush()
+            .await
+            .map_err(|err| self.tracked(crate::WebsocketError::MessageFlush(err.into())))
+    }
+
+    async fn send_fragment(&mut self, text: bool, data: Bytes, first: bool, fin: bool) -> crate::Result<()> {
+        if let Some(err) = poll_fn(|cx| Poll::Ready(self.poll_expiry(cx))).await {
+            return Err(err);
+        }
+
+        let opcode = match (first, text) {
+            (false, _) => Data::Continue,
+            (true, true) => Data::Text,
+            (true, false) => Data::Binary,
+        };
+        metrics::fragment(Direction::Out, text, data.len(), first);
+        self.span.fragment(Direction::Out, text, data.len());
+
+        let frame = Frame::message(data, OpCode::Data(opcode), fin);
+        self.inner
+            .feed(protocol::Message::Frame(frame))
+            .await
+            .map_err(|err| self.tracked(crate::WebsocketError::MessageSend(err.into())))
+    }
+
+    /// Reads the next item from the transport, handing the diverted fragments over in the order they arrived.
+    pub(crate) fn poll_item(&mut self, cx: &mut Context) -> Poll<Option<Result<Item, Error>>> {
+        loop {
+            if let Some(item) = self.backlog.pop_front() {
+                match item {
+                    Item::Fragment(Fragment::Chunk(_)) if self.discarding => continue,
+                    Item::Fragment(Fragment::End) => {
+                        self.restore_deferred();
+                        if self.discarding {
+                            self.discarding = false;
+                            continue;
+                        }
+                        return Poll::Ready(Some(Ok(item)));
+                    }
+                    item => return Poll::Ready(Some(Ok(item))),
+                }
+            }
+
+            if let Some(err) = self.read_error.take() {
+                return Poll::Ready(Some(Err(err)));
+            }
+
+            let polled = Pin::new(&mut self.inner).poll_next(cx);
+            if let Some(filter) = self.inner.filter_mut() {
+                self.backlog.extend(filter.take_fragments().map(Item::Fragment));
+            }
+
+            match polled {
+                Poll::Ready(Some(Ok(msg))) => self.backlog.push_back(Item::Message(msg)),
+                Poll::Ready(Some(Err(err))) => self.read_error = Some(err),
+                Poll::Ready(None) if self.backlog.is_empty() => return Poll::Ready(None),
+                Poll::Pending if self.backlog.is_empty() => return Poll::Pending,
+                _ => {}
+            }
+        }
+    }
+
+    /// Holds a message received in the middle of a streamed message until the end of it.
+    pub(crate) fn defer(&mut self, msg: protocol::Message) {
+        self.deferred.push_back(msg);
+    }
+
+    /// Puts the messages held during a streamed message back in front of the received ones.
+    pub(crate) fn restore_deferred(&mut self) {
+        while let Some(msg) = self.deferred.pop_back() {
+            self.backlog.push_front(Item::Message(msg));
+        }
+    }
+
+    /// Appends a chunk to the fragmented message being assembled, up to the `max_message_size`.
+    fn assemble(&mut self, chunk: Bytes) -> Result<(), CapacityError> {
+        if let Some((_, ref mut data)) = self.assembling {
+            let size = data.len() + chunk.len();
+            match self.max_message_size {
+                Some(max_size) if size > max_size => {
+                    self.assembling = None;
+                    self.discarding = true;
+                    return Err(CapacityError::MessageTooLong { size, max_size });
+                }
+                _ => data.extend_from_slice(&chunk),
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until the rate limit allows reading the next message, or the next chunk of a streamed message.
+    pub(crate) fn poll_read_delay(&mut self, cx: &mut Context) -> Poll<()> {
+        if let Some(ref mut delay) = self.read_delay {
+            ready!(delay.as_mut().poll(cx));
+            self.read_delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Charges a chunk of a streamed message to the byte budget of the rate limit. The rest of the message is skipped
+    /// if the chunk exceeds the budget.
+    pub(crate) fn charge_chunk(&mut self, cx: &mut Context, len: usize) -> crate::Result<()> {
+        let verdict = match self.rate_limiter.as_mut() {
+            Some(limiter) => limiter.check_chunk(len),
+            None => return Ok(()),
+        };
+
+        match verdict {
+            Verdict::Allow => Ok(()),
+            Verdict::DelayNext(deadline) => {
+                self.read_delay = Some(Box::pin(tokio::time::sleep_until(deadline)));
+                Ok(())
+            }
+            Verdict::Drop => {
+                self.discarding = true;
+                Err(self.tracked(crate::WebsocketError::RateLimitExceeded))
+            }
+            Verdict::Close => {
+                self.discarding = true;
+                Err(self.close_rate_limited(cx))
+            }
+        }
+    }
+
+    /// Closes the connection for exceeding its rate limit.
+    fn close_rate_limited(&mut self, cx: &mut Context) -> crate::WebsocketError {
+        self.rate_limiter = None;
+        let frame = CloseFrame {
+            code: CloseCode::Policy,
+            reason: "Rate limit exceeded".into(),
+        };
+        self.close_for(cx, frame, crate::WebsocketError::RateLimitExceeded)
+    }
+
+    fn poll_receive(&mut self, cx: &mut Context, streaming: bool) -> Poll<Option<crate::Result<Received>>> {
+        if let Err(err) = ready!(self.poll_pending_close(cx)) {
+            return Poll::Ready(Some(Err(err)));
+        }
+
+        if let Some(err) = self.poll_expiry(cx) {
+            return Poll::Ready(Some(Err(err)));
+        }
+
+        ready!(self.poll_read_delay(cx));
+
+        loop {
+            // The expiry may have been extended by the previous message.
+            if let Some(err) = self.poll_expiry(cx) {
+                return Poll::Ready(Some(Err(err)));
+            }
+
+            let item = match ready!(self.poll_item(cx)) {
+                Some(Ok(item)) => item,
+                Some(Err(err)) => return Poll::Ready(Some(Err(self.receive_failed(cx, err)))),
+                None => return Poll::Ready(None),
+            };
+
+            let received = match item {
+                Item::Message(msg) => Received::Message(msg),
+                Item::Fragment(Fragment::Start { text }) if streaming => Received::Stream { text },
+                Item::Fragment(Fragment::Start { text }) => {
+                    self.assembling = Some((text, Vec::new()));
+                    continue;
+                }
+                Item::Fragment(Fragment::Chunk(chunk)) => match self.assemble(chunk) {
+                    Ok(()) => continue,
+                    Err(err) => return Poll::Ready(Some(Err(self.receive_failed(cx, Error::Capacity(err))))),
+                },
+                Item::Fragment(Fragment::End) => match self.assembling.take() {
+                    Some((true, data)) => match String::from_utf8(data) {
+                        Ok(text) => Received::Message(protocol::Message::text(text)),
+                        Err(_) => return Poll::Ready(Some(Err(self.receive_failed(cx, Error::Utf8)))),
+                    },
+                    Some((false, data)) => Received::Message(protocol::Message::binary(data)),
+                    None => continue,
+                },
+            };
+
+            let len = match received {
+                Received::Message(ref msg) => {
+                    self.observe(Direction::In, msg);
+                    Some(msg.len()).filter(|_| !msg.is_close())
+                }
+                Received::Stream { .. } => Some(0),
+            };
+
+            if let (Some(limiter), Some(len)) = (self.rate_limiter.as_mut(), len) {
+                match limiter.check(len) {
+                    Verdict::Allow => {}
+                    Verdict::DelayNext(deadline) => {
+                        self.read_delay = Some(Box::pin(tokio::time::sleep_until(deadline)))
+                    }
+                    Verdict::Drop => {
+                        if let Received::Stream { .. } = received {
+                            self.discarding = true;
+                        }
+                        continue;
+                    }
+                    Verdict::Close => return Poll::Ready(Some(Err(self.close_rate_limited(cx)))),
+                }
+            }
+
+            let received = match received {
+                Received::Message(inner) => match self.run_middlewares(Direction::In, Message { inner }) {
+                    MiddlewareFlow::Continue(msg) if self.refresh_session(&msg) => continue,
+                    MiddlewareFlow::Continue(msg) => Received::Message(msg.inner),
+                    MiddlewareFlow::Drop => continue,
+                    MiddlewareFlow::Close(code, reason) => {
+                        let frame = CloseFrame {
+                            code,
+                            reason: close_reason(reason),
+                        };
+                        let err = self.close_for(cx, frame, crate::WebsocketError::ClosedByMiddleware(code));
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                },
+                received => received,
+            };
+
+            return Poll::Ready(Some(Ok(received)));
+        }
+    }
+
+    /// Splits the connection into a [`WebSocketReader`](./struct.WebSocketReader.html) receiving the messages and a
+    /// [`WebSocketWriter`](./struct.WebSocketWriter.html) sending them, so they can be used from different tasks.
+    ///
+    /// Unlike `StreamExt::split`, which returns the sink before the stream, both halves keep the connection metadata
+    /// and the [extensions](#method.extensions), and they can be [reunited](./struct.WebSocketReader.html#method.reunite)
+    /// into the `WebSocket`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::{SinkExt, StreamExt};
+    /// use routerify_websocket::{Message, WebSocket};
+    ///
+    /// async fn ws_handler(ws: WebSocket) {
+    ///     let (mut reader, mut writer) = ws.into_split();
+    ///
+    ///     // Send a heartbeat from another task.
+    ///     let heartbeat = tokio::spawn(async move {
+    ///         let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+    ///         for _ in 0..10 {
+    ///             interval.tick().await;
+    ///             writer.send(Message::text("heartbeat")).await.unwrap();
+    ///         }
+    ///         writer
+    ///     });
+    ///
+    ///     while let Some(Ok(msg)) = reader.next().await {
+    ///         println!("{} sent {:?}", reader.remote_addr(), msg);
+    ///     }
+    ///
+    ///     let writer = heartbeat.await.unwrap();
+    ///     let ws = reader.reunite(writer).unwrap();
+    ///     ws.close().await.unwrap();
+    /// }
+    /// ```
+    pub fn into_split(self) -> (WebSocketReader<S>, WebSocketWriter<S>) {
+        crate::split::split(self)
+    }
+
     /// Consumes the websocket connection and gracefully closes it.
     pub async fn close(self) -> crate::Result<()> {
         let mut this = self;
+        this.observe(Direction::Out, &protocol::Message::Close(None));
         this.inner
-            .close(None)
+            .send(protocol::Message::Close(None))
             .await
-            .map_err(|err| crate::WebsocketError::WebSocketClose(err.into()))
+            .map_err(|err| this.tracked(crate::WebsocketError::WebSocketClose(err.into())))
     }
 
     /// Consumes the websocket connection and gracefully closes it with a code and reason.
     pub async fn close_with<R: Into<Cow<'static, str>>>(self, code: CloseCode, reason: R) -> crate::Result<()> {
         let mut this = self;
+        let frame = CloseFrame {
+            code,
+            reason: close_reason(reason.into()),
+        };
+        this.observe(Direction::Out, &protocol::Message::Close(Some(frame.clone())));
         this.inner
-            .close(Some(CloseFrame {
-                code,
-                reason: reason.into(),
-            }))
+            .send(protocol::Message::Close(Some(frame)))
             .await
-            .map_err(|err| crate::WebsocketError::WebSocketClose(err.into()))
+            .map_err(|err| this.tracked(crate::WebsocketError::WebSocketClose(err.into())))
     }
 }
 
-impl Stream for WebSocket {
+impl<S> WebSocket<S> {
+    /// Skips the rest of the streamed message.
+    pub(crate) fn discard_stream(&mut self) {
+        self.discarding = true;
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for WebSocket<S> {
     type Item = Result<Message, crate::WebsocketError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
-            Some(Ok(item)) => Poll::Ready(Some(Ok(Message { inner: item }))),
-            Some(Err(err)) => Poll::Ready(Some(Err(crate::WebsocketError::MessageReceive(err.into())))),
+        match ready!(self.poll_receive(cx, false)) {
+            Some(Ok(Received::Message(inner))) => Poll::Ready(Some(Ok(Message { inner }))),
+            // The fragmented messages are assembled when they are not streamed.
+            Some(Ok(Received::Stream { .. })) => unreachable!(),
+            Some(Err(err)) => Poll::Ready(Some(Err(err))),
             None => Poll::Ready(None),
         }
     }
 }
 
-impl Sink<Message> for WebSocket {
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<Message> for WebSocket<S> {
     type Error = crate::WebsocketError;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(err) = self.poll_expiry(cx) {
+            return Poll::Ready(Err(err));
+        }
+        // A close frame queued by the stream goes out before the next message.
+        ready!(self.poll_pending_close(cx))?;
+
         match ready!(Pin::new(&mut self.inner).poll_ready(cx)) {
             Ok(()) => Poll::Ready(Ok(())),
-            Err(err) => Poll::Ready(Err(crate::WebsocketError::ReadyStatus(err.into()))),
+            Err(err) => Poll::Ready(Err(self.tracked(crate::WebsocketError::ReadyStatus(err.into())))),
         }
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let item = match self.run_middlewares(Direction::Out, item) {
+            MiddlewareFlow::Continue(msg) => msg,
+            MiddlewareFlow::Drop => return Ok(()),
+            MiddlewareFlow::Close(code, reason) => Message::close_with(code, reason),
+        };
+
+        // The fallback transport only carries whole messages.
+        if matches!(item.inner, protocol::Message::Frame(_)) && self.inner.filter_mut().is_none() {
+            return Err(self.tracked(crate::WebsocketError::UnsupportedMessage));
+        }
+
+        self.observe(Direction::Out, &item.inner);
         match Pin::new(&mut self.inner).start_send(item.inner) {
             Ok(()) => Ok(()),
-            Err(err) => Err(crate::WebsocketError::MessageSend(err.into())),
+            Err(err) => Err(self.tracked(crate::WebsocketError::MessageSend(err.into()))),
         }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        if let Some(err) = self.poll_expiry(cx) {
+            return Poll::Ready(Err(err));
+        }
+
         match ready!(Pin::new(&mut self.inner).poll_flush(cx)) {
             Ok(()) => Poll::Ready(Ok(())),
-            Err(err) => Poll::Ready(Err(crate::WebsocketError::MessageFlush(err.into()))),
+            Err(err) => Poll::Ready(Err(self.tracked(crate::WebsocketError::MessageFlush(err.into())))),
         }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
         match ready!(Pin::new(&mut self.inner).poll_close(cx)) {
             Ok(()) => Poll::Ready(Ok(())),
-            Err(err) => Poll::Ready(Err(crate::WebsocketError::WebSocketClose(err.into()))),
+            Err(err) => Poll::Ready(Err(self.tracked(crate::WebsocketError::WebSocketClose(err.into())))),
         }
     }
 }
 
-impl fmt::Debug for WebSocket {
+impl<S> fmt::Debug for WebSocket<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("WebSocket").finish()
     }